@@ -1,14 +1,29 @@
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Deserializer};
+use std::fmt;
+use std::rc::Rc;
 
-#[derive(PartialEq,Debug,Serialize,Deserialize,Clone)]
+/// Members of an object literal. They are shared between copies of the
+/// tree, so the runtime identifies the literal by their address.
+#[allow(clippy::vec_box)]
+pub type Members = Rc<Vec<Box<AST>>>;
+
+// The shape mirrors the JSON produced by the FML parser, keep it as is.
+#[allow(clippy::vec_box, clippy::upper_case_acronyms)]
+#[derive(PartialEq,Debug,Serialize,Deserialize,Clone,Default)]
 pub enum AST {
     Integer(i32),
+    /** Integer literal that doesn't fit in 32 bits, kept as decimal digits. */
+    #[serde(deserialize_with = "digits")]
+    BigInteger(String),
+    Float(f64),
+    String(String),
     Boolean(bool),
+    #[default]
     Null,
 
-    Variable { name: String, value: Box<AST> },
+    Variable { name: String, #[serde(default, skip_serializing_if = "Option::is_none")] annotation: Option<Type>, value: Box<AST> },
     Array { size: Box<AST>, value: Box<AST> },
-    Object { extends: Box<AST>, members: Vec<Box<AST>> },
+    Object { extends: Box<AST>, members: Members },
 
     AccessVariable { name: String },
     AccessField { object: Box<AST>, field: String },
@@ -18,7 +33,7 @@ pub enum AST {
     AssignField { object: Box<AST>, field: String, value: Box<AST> },
     AssignArray { array: Box<AST>, index: Box<AST>, value: Box<AST> },
 
-    Function { name: String, parameters: Vec<String>, body: Box<AST> },
+    Function { name: String, parameters: Vec<String>, #[serde(default, skip_serializing_if = "Signature::is_empty")] signature: Signature, body: Box<AST> },
     // Operator { operator: Operator, parameters: Vec<String>, body: Box<AST> },    // TODO Consider merging with function
 
     CallFunction { name: String, arguments: Vec<Box<AST>> },
@@ -27,11 +42,104 @@ pub enum AST {
     //Operation { operator: Operator, left: Box<AST>, right: Box<AST> },               // TODO Consider removing
 
     Top (Vec<Box<AST>>),
+    /** Loads FML program from 'path' relative to the importing file. With 'name' the module is bound to it as an object. */
+    Import { path: String, #[serde(default)] name: Option<String> },
     Block (Vec<Box<AST>>),
     Loop { condition: Box<AST>, body: Box<AST> },
+    /** Iterates over 'range(a, b)', an array or an object with 'has_next' and 'next' methods. */
+    For { name: String, iterable: Box<AST>, body: Box<AST> },
     Conditional { condition: Box<AST>, consequent: Box<AST>, alternative: Box<AST> },
 
     Print { format: String, arguments: Vec<Box<AST>> },
+
+    Throw { value: Box<AST> },
+    /** Thrown value is bound to 'name' in the 'handler', 'finally' is optional. */
+    Try { body: Box<AST>, name: String, handler: Box<AST>, #[serde(default)] finally: Box<AST> },
+
+    Return { value: Box<AST> },
+    Break,
+    Continue,
+}
+
+/** Types that can be declared by annotations, 'Any' is the same as no annotation. */
+#[derive(PartialEq,Eq,Debug,Serialize,Deserialize,Clone,Copy)]
+pub enum Type {
+    Int,
+    Bool,
+    Null,
+    Array,
+    Object,
+    Any,
+}
+
+impl Type {
+    pub fn from_name(name: &str) -> Option<Type> {
+        match name {
+            "int" => Some(Type::Int),
+            "bool" => Some(Type::Bool),
+            "null" => Some(Type::Null),
+            "array" => Some(Type::Array),
+            "object" => Some(Type::Object),
+            "any" => Some(Type::Any),
+            _ => None,
+        }
+    }
+
+    /** Returns true if values of type 'other' can be used where 'self' is declared. */
+    pub fn accepts(self, other: Type) -> bool {
+        self == Type::Any || other == Type::Any || self == other
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Type::Int => "int",
+            Type::Bool => "bool",
+            Type::Null => "null",
+            Type::Array => "array",
+            Type::Object => "object",
+            Type::Any => "any",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/** Annotations of a function, 'parameters' is either empty or has a type for every parameter. */
+#[derive(PartialEq,Debug,Serialize,Deserialize,Clone,Default)]
+pub struct Signature {
+    #[serde(default)]
+    pub parameters: Vec<Option<Type>>,
+    #[serde(default)]
+    pub returns: Option<Type>,
+}
+
+impl Signature {
+    pub fn is_empty(&self) -> bool {
+        self.parameters.is_empty() && self.returns.is_none()
+    }
+
+    /** Declared type of the parameter at 'index'. */
+    pub fn parameter(&self, index: usize) -> Option<Type> {
+        self.parameters.get(index).copied().flatten()
+    }
+}
+
+/// Big integer literals can be written either as a JSON number or as
+/// a string, the latter doesn't lose precision beyond 64 bits.
+fn digits<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Digits {
+        Signed(i64),
+        Unsigned(u64),
+        Text(String),
+    }
+    Ok(match Digits::deserialize(deserializer)? {
+        Digits::Signed(val) => val.to_string(),
+        Digits::Unsigned(val) => val.to_string(),
+        Digits::Text(val) => val,
+    })
 }
 
 impl AST {
@@ -41,4 +149,27 @@ impl AST {
         Box::new(self)
     }
 
+    /** Direct subtrees of the node, in the order they are evaluated. */
+    pub fn children(&self) -> Vec<&AST> {
+        match self {
+            AST::Integer(_) | AST::BigInteger(_) | AST::Float(_) | AST::String(_) | AST::Boolean(_) | AST::Null
+            | AST::AccessVariable{..} | AST::Import{..} | AST::Break | AST::Continue => Vec::new(),
+            AST::Variable{name:_, annotation:_, value} | AST::AssignVariable{name:_, value}
+            | AST::Throw{value} | AST::Return{value} => vec![value],
+            AST::Array{size, value} => vec![size, value],
+            AST::Object{extends, members} => members.iter().map(|member| &**member).chain([&**extends]).collect(),
+            AST::AccessField{object, field:_} => vec![object],
+            AST::AccessArray{array, index} => vec![array, index],
+            AST::AssignField{object, field:_, value} => vec![value, object],
+            AST::AssignArray{array, index, value} => vec![array, index, value],
+            AST::Function{name:_, parameters:_, signature:_, body} => vec![body],
+            AST::CallFunction{name:_, arguments} | AST::Print{format:_, arguments} => arguments.iter().map(|argument| &**argument).collect(),
+            AST::CallMethod{object, name:_, arguments} => [&**object].into_iter().chain(arguments.iter().map(|argument| &**argument)).collect(),
+            AST::Top(exprs) | AST::Block(exprs) => exprs.iter().map(|expr| &**expr).collect(),
+            AST::Loop{condition, body} => vec![condition, body],
+            AST::For{name:_, iterable, body} => vec![iterable, body],
+            AST::Conditional{condition, consequent, alternative} => vec![condition, consequent, alternative],
+            AST::Try{body, name:_, handler, finally} => vec![body, handler, finally],
+        }
+    }
 }
@@ -0,0 +1,605 @@
+use crate::ast::{AST, Signature, Type};
+use crate::interpreter::{builtin_arity, read_program};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Problem found in the program, 'function' is the function or method
+/// it was found in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub function: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match &self.function {
+            Some(function) => write!(f, "{} in function '{}': {}", severity, function, self.message),
+            None => write!(f, "{}: {}", severity, self.message),
+        }
+    }
+}
+
+/// Variable declared by 'let' or bound by the language itself, only
+/// the former are reported when they are never read.
+struct Binding {
+    name: String,
+    annotation: Option<Type>,
+    declared: bool,
+    used: bool,
+}
+
+/// Parameters of a top level function, used to check its calls.
+struct Declaration {
+    parameters: Vec<String>,
+    signature: Signature,
+}
+
+/// Mirrors environments of the runtime: every block, loop, iteration,
+/// handler and call gets its own scope, top level declarations go to
+/// the globals. Types are inferred along the way, values of unknown
+/// type are 'any' and are accepted everywhere.
+struct Checker {
+    functions: HashMap<String, Declaration>,
+    /// False if some imported module couldn't be read, its functions are unknown then.
+    complete: bool,
+    globals: HashMap<String, Option<Type>>,
+    declared_globals: HashSet<String>,
+    scopes: Vec<Vec<Binding>>,
+    function: Option<String>,
+    /// Declared type of the result of the current function.
+    returns: Option<Type>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Operators of ints, the ones not listed are comparisons.
+const INT_OPERATORS: [&str; 11] = ["+", "-", "*", "/", "%", "&", "|", "^", "<<", ">>", ">>>"];
+const COMPARISONS: [&str; 6] = ["==", "!=", "<", "<=", ">", ">="];
+const BOOL_OPERATORS: [&str; 4] = ["&", "|", "==", "!="];
+
+/// Type of the result of the built-in function, if it's always the same.
+fn builtin_type(name: &str) -> Type {
+    match name {
+        "range" => Type::Array,
+        "arg_count" | "to_int" => Type::Int,
+        _ => Type::Any,
+    }
+}
+
+/// Type of a value that is either 'left' or 'right'.
+fn join(left: Type, right: Type) -> Type {
+    if left == right { left } else { Type::Any }
+}
+
+impl Checker {
+    fn report(&mut self, severity: Severity, message: String) {
+        self.diagnostics.push(Diagnostic{severity, function: self.function.clone(), message});
+    }
+
+    /// Reports value of type 'actual' used where 'declared' is required,
+    /// 'what' describes the annotated thing.
+    fn expect(&mut self, declared: Option<Type>, actual: Type, what: impl FnOnce() -> String) {
+        if let Some(declared) = declared {
+            if !declared.accepts(actual) {
+                self.report(Severity::Error, format!("{} is declared as {}, got {}.", what(), declared, actual));
+            }
+        }
+    }
+
+    /// Collects functions and globals of the top level statements, including
+    /// the ones brought in by imports without a name. Such imports may also be
+    /// nested anywhere in the program, their functions are collected as well.
+    fn collect(&mut self, stmts: &[Box<AST>], base: &Path, visited: &mut HashSet<PathBuf>) {
+        for stmt in stmts {
+            match &**stmt {
+                AST::Function{name, parameters, signature, body:_} => {
+                    self.functions.insert(name.clone(), Declaration{parameters: parameters.clone(), signature: signature.clone()});
+                }
+                AST::Variable{name, annotation, value:_} => {
+                    self.globals.insert(name.clone(), *annotation);
+                }
+                AST::Import{path:_, name: Some(name)} => {
+                    self.globals.insert(name.clone(), Some(Type::Object));
+                }
+                AST::Import{path, name: None} => self.collect_import(path, base, visited),
+                stmt => self.collect_nested(stmt, base, visited),
+            }
+        }
+    }
+
+    fn collect_import(&mut self, path: &str, base: &Path, visited: &mut HashSet<PathBuf>) {
+        let path = match base.join(path).canonicalize() {
+            Ok(path) => path,
+            Err(_) => {
+                self.complete = false;
+                return;
+            }
+        };
+        if !visited.insert(path.clone()) {
+            return;
+        }
+        match read_program(&path) {
+            Ok(AST::Top(stmts)) => self.collect(&stmts, path.parent().unwrap_or(Path::new("")), visited),
+            _ => self.complete = false,
+        }
+    }
+
+    /// Collects imports without a name found below the node.
+    fn collect_nested(&mut self, ast: &AST, base: &Path, visited: &mut HashSet<PathBuf>) {
+        for child in ast.children() {
+            match child {
+                AST::Import{path, name: None} => self.collect_import(path, base, visited),
+                child => self.collect_nested(child, base, visited),
+            }
+        }
+    }
+
+    fn push_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    fn pop_scope(&mut self) {
+        let scope = self.scopes.pop().expect("Missing scope.");
+        for binding in scope {
+            if binding.declared && !binding.used && !binding.name.starts_with('_') {
+                self.report(Severity::Warning, format!("Variable '{}' is never used.", binding.name));
+            }
+        }
+    }
+
+    /// Binds variable that isn't declared by 'let', like parameters or 'this'.
+    fn bind(&mut self, name: &str, annotation: Option<Type>) {
+        let scope = self.scopes.last_mut().expect("Missing scope.");
+        scope.push(Binding{name: name.to_string(), annotation, declared: false, used: false});
+    }
+
+    fn declare(&mut self, name: &str, annotation: Option<Type>) {
+        let Some((scope, outer)) = self.scopes.split_last_mut() else {
+            if !self.declared_globals.insert(name.to_string()) {
+                self.report(Severity::Error, format!("Variable '{}' is redeclared.", name));
+            }
+            self.globals.insert(name.to_string(), annotation);
+            return;
+        };
+        if scope.iter().any(|binding| binding.name == name) {
+            self.report(Severity::Error, format!("Variable '{}' is redeclared in the same block.", name));
+            return;
+        }
+        scope.push(Binding{name: name.to_string(), annotation, declared: true, used: false});
+        let shadows = outer.iter().flatten().any(|binding| binding.name == name) || self.globals.contains_key(name);
+        if shadows {
+            self.report(Severity::Warning, format!("Variable '{}' shadows an outer variable.", name));
+        }
+    }
+
+    /// Returns the binding of variable 'name', None for globals and unknown variables.
+    fn lookup(&mut self, name: &str) -> Option<&mut Binding> {
+        self.scopes.iter_mut().rev().flatten().find(|binding| binding.name == name)
+    }
+
+    /// Declared type of the variable, None if it isn't annotated.
+    fn annotation(&mut self, name: &str) -> Option<Type> {
+        match self.lookup(name) {
+            Some(binding) => binding.annotation,
+            None => self.globals.get(name).copied().flatten(),
+        }
+    }
+
+    fn check_call(&mut self, name: &str, arguments: &[Type]) -> Type {
+        if let Some(declaration) = self.functions.get(name) {
+            let expected = declaration.parameters.len();
+            if expected != arguments.len() {
+                self.report(Severity::Error, format!("Wrong number of arguments in function call '{}', expected {}, got {}.",
+                    name, expected, arguments.len()));
+                return Type::Any;
+            }
+            let parameters: Vec<(String, Option<Type>)> = declaration.parameters.iter().enumerate()
+                .map(|(index, parameter)| (parameter.clone(), declaration.signature.parameter(index)))
+                .collect();
+            let returns = declaration.signature.returns.unwrap_or(Type::Any);
+            for ((parameter, declared), actual) in parameters.into_iter().zip(arguments) {
+                self.expect(declared, *actual, || format!("Parameter '{}'", parameter));
+            }
+            return returns;
+        }
+        match builtin_arity(name) {
+            Some(expected) if expected != arguments.len() => {
+                self.report(Severity::Error, format!("Wrong number of arguments in function call '{}', expected {}, got {}.",
+                    name, expected, arguments.len()));
+                Type::Any
+            }
+            Some(_) => builtin_type(name),
+            None => {
+                if self.complete {
+                    self.report(Severity::Error, format!("Called function '{}' is not defined.", name));
+                }
+                Type::Any
+            }
+        }
+    }
+
+    /// Infers result of operator 'name' called on a value of type 'left',
+    /// reports combinations that always fail.
+    fn check_operator(&mut self, left: Type, name: &str, arguments: &[Type]) -> Type {
+        match (left, arguments) {
+            (Type::Int, []) if name == "-" || name == "~" => Type::Int,
+            (Type::Bool, []) if name == "!" => Type::Bool,
+            (Type::Int | Type::Bool, []) if ["-", "~", "!"].contains(&name) => {
+                self.report(Severity::Error, format!("Operator '{}' can't be used on {}.", name, left));
+                Type::Any
+            }
+            (Type::Null, [_]) | (_, [Type::Null]) if name == "==" || name == "!=" => Type::Bool,
+            (Type::Int, [Type::Int]) if INT_OPERATORS.contains(&name) => Type::Int,
+            (Type::Int, [Type::Int]) if COMPARISONS.contains(&name) => Type::Bool,
+            (Type::Bool, [Type::Bool]) if BOOL_OPERATORS.contains(&name) => Type::Bool,
+            (Type::Int | Type::Bool, [Type::Int | Type::Bool | Type::Null | Type::Array | Type::Object])
+            | (Type::Int, [Type::Any]) if !INT_OPERATORS.contains(&name) && !COMPARISONS.contains(&name) => {
+                self.report(Severity::Error, format!("Unknown operator '{}' on {}.", name, left));
+                Type::Any
+            }
+            (Type::Int | Type::Bool, [right]) if *right != Type::Any => {
+                self.report(Severity::Error, format!("Operator '{}' can't be used on {} and {}.", name, left, right));
+                Type::Any
+            }
+            (Type::Int, [_]) if COMPARISONS.contains(&name) => Type::Bool,
+            _ => Type::Any,
+        }
+    }
+
+    /// Checks body of a function or method in its own call frame.
+    fn check_function(&mut self, name: &str, parameters: &[String], signature: &Signature, body: &AST, method: bool) {
+        let scopes = std::mem::take(&mut self.scopes);
+        let function = self.function.replace(name.to_string());
+        let returns = std::mem::replace(&mut self.returns, signature.returns);
+        self.push_scope();
+        for (index, parameter) in parameters.iter().enumerate() {
+            self.bind(parameter, signature.parameter(index));
+        }
+        if method {
+            self.bind("this", Some(Type::Object));
+        }
+        let result = self.check(body);
+        self.expect(signature.returns, result, || String::from("Returned value"));
+        self.pop_scope();
+        self.returns = returns;
+        self.function = function;
+        self.scopes = scopes;
+    }
+
+    fn check_scoped(&mut self, ast: &AST, binding: Option<&str>) -> Type {
+        self.push_scope();
+        if let Some(name) = binding {
+            self.bind(name, None);
+        }
+        let result = self.check(ast);
+        self.pop_scope();
+        result
+    }
+
+    /// Checks branches of a conditional. Only one of them runs, so both may
+    /// declare the same variable, the declarations of both are kept.
+    fn check_branches(&mut self, consequent: &AST, alternative: &AST) -> Type {
+        let Some(start) = self.scopes.last().map(Vec::len) else {
+            let before = self.declared_globals.clone();
+            let consequent = self.check(consequent);
+            let declared = std::mem::replace(&mut self.declared_globals, before);
+            let alternative = self.check(alternative);
+            self.declared_globals.extend(declared);
+            return join(consequent, alternative);
+        };
+        let consequent = self.check(consequent);
+        let declared: Vec<Binding> = self.scopes.last_mut().expect("Missing scope.").drain(start..).collect();
+        let alternative = self.check(alternative);
+        let scope = self.scopes.last_mut().expect("Missing scope.");
+        for binding in declared {
+            match scope[start..].iter_mut().find(|other| other.name == binding.name) {
+                Some(other) => other.used |= binding.used,
+                None => scope.push(binding),
+            }
+        }
+        join(consequent, alternative)
+    }
+
+    fn check_all(&mut self, exprs: &[Box<AST>]) -> Vec<Type> {
+        exprs.iter().map(|expr| self.check(expr)).collect()
+    }
+
+    /// Checks the node and returns type of its value.
+    fn check(&mut self, ast: &AST) -> Type {
+        match ast {
+            AST::Integer(_) | AST::BigInteger(_) => Type::Int,
+            AST::Boolean(_) => Type::Bool,
+            AST::Null => Type::Null,
+            AST::Float(_) | AST::String(_) | AST::Break | AST::Continue => Type::Any,
+            AST::Import{path:_, name: None} => Type::Null,
+            AST::Variable{name, annotation, value} => {
+                let result = self.check(value);
+                self.expect(*annotation, result, || format!("Variable '{}'", name));
+                self.declare(name, *annotation);
+                result
+            }
+            AST::Import{path:_, name: Some(name)} => {
+                self.declare(name, Some(Type::Object));
+                Type::Object
+            }
+            AST::Array{size, value} => {
+                let size = self.check(size);
+                self.expect(Some(Type::Int), size, || String::from("Array size"));
+                self.check(value);
+                Type::Array
+            }
+            AST::Object{extends, members} => {
+                for member in members.iter() {
+                    match &**member {
+                        AST::Variable{name, annotation, value} => {
+                            let result = self.check(value);
+                            self.expect(*annotation, result, || format!("Field '{}'", name));
+                        }
+                        AST::Function{name, parameters, signature, body} => self.check_function(name, parameters, signature, body, true),
+                        _ => self.report(Severity::Error, String::from("Object can only contain variables or methods.")),
+                    }
+                }
+                self.check(extends);
+                Type::Object
+            }
+            AST::AccessVariable{name} => {
+                if let Some(binding) = self.lookup(name) {
+                    binding.used = true;
+                }
+                self.annotation(name).unwrap_or(Type::Any)
+            }
+            AST::AccessField{object, field:_} => {
+                self.check(object);
+                Type::Any
+            }
+            AST::AccessArray{array, index} => {
+                self.check(array);
+                self.check(index);
+                Type::Any
+            }
+            AST::AssignVariable{name, value} => {
+                let result = self.check(value);
+                let annotation = self.annotation(name);
+                self.expect(annotation, result, || format!("Variable '{}'", name));
+                result
+            }
+            AST::AssignField{object, field:_, value} => {
+                let result = self.check(value);
+                self.check(object);
+                result
+            }
+            AST::AssignArray{array, index, value} => {
+                self.check(array);
+                self.check(index);
+                self.check(value)
+            }
+            AST::Function{name, parameters, signature, body} => {
+                self.report(Severity::Error, format!("Function '{}' can only be declared as top level statement.", name));
+                self.check_function(name, parameters, signature, body, false);
+                Type::Null
+            }
+            AST::CallFunction{name, arguments} => {
+                let arguments = self.check_all(arguments);
+                self.check_call(name, &arguments)
+            }
+            AST::CallMethod{object, name, arguments} => {
+                let object = self.check(object);
+                let arguments = self.check_all(arguments);
+                self.check_operator(object, name, &arguments)
+            }
+            AST::Top(stmts) => {
+                let mut result = Type::Int;
+                for stmt in stmts {
+                    match &**stmt {
+                        AST::Function{name, parameters, signature, body} => self.check_function(name, parameters, signature, body, false),
+                        stmt => result = self.check(stmt),
+                    }
+                }
+                result
+            }
+            AST::Block(exprs) => {
+                self.push_scope();
+                let result = self.check_all(exprs).pop().unwrap_or(Type::Null);
+                self.pop_scope();
+                result
+            }
+            AST::Loop{condition, body} => {
+                self.push_scope();
+                self.check(condition);
+                self.check(body);
+                self.pop_scope();
+                Type::Null
+            }
+            AST::For{name, iterable, body} => {
+                self.check(iterable);
+                self.check_scoped(body, Some(name));
+                Type::Null
+            }
+            AST::Conditional{condition, consequent, alternative} => {
+                self.check(condition);
+                self.check_branches(consequent, alternative)
+            }
+            AST::Print{format:_, arguments} => {
+                self.check_all(arguments);
+                Type::Null
+            }
+            AST::Throw{value} => {
+                self.check(value);
+                Type::Any
+            }
+            AST::Return{value} => {
+                let result = self.check(value);
+                self.expect(self.returns, result, || String::from("Returned value"));
+                Type::Any
+            }
+            AST::Try{body, name, handler, finally} => {
+                self.check(body);
+                self.check_scoped(handler, Some(name));
+                self.check(finally);
+                Type::Any
+            }
+        }
+    }
+}
+
+/// Checks the program without running it and returns all errors and
+/// warnings found. 'path' is the file the program was read from, functions
+/// of imported modules are looked up relative to it.
+pub fn check(ast: &AST, path: Option<&Path>) -> Vec<Diagnostic> {
+    let mut checker = Checker{
+        functions: HashMap::new(),
+        complete: true,
+        globals: HashMap::from([(String::from("args"), Some(Type::Array))]),
+        declared_globals: HashSet::from([String::from("args")]),
+        scopes: Vec::new(),
+        function: None,
+        returns: None,
+        diagnostics: Vec::new(),
+    };
+    if let AST::Top(stmts) = ast {
+        let base = path.and_then(Path::parent).unwrap_or(Path::new(""));
+        checker.collect(stmts, base, &mut HashSet::new());
+    }
+    checker.check(ast);
+    checker.diagnostics
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse;
+
+    fn messages(source: &str) -> Vec<String> {
+        check(&parse(source).unwrap(), None).iter().map(|diagnostic| diagnostic.to_string()).collect()
+    }
+
+    #[test]
+    fn examples_have_no_errors() {
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "fml") {
+                let diagnostics = check(&read_program(&path).unwrap(), Some(&path));
+                let errors: Vec<&Diagnostic> = diagnostics.iter().filter(|diagnostic| diagnostic.severity == Severity::Error).collect();
+                assert!(errors.is_empty(), "{}: {:?}", path.display(), errors);
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(messages("\
+function f(x) -> begin
+    let y = x;
+    let y = 2;
+    g(y)
+end;
+f(1, 2);
+range(1);
+let o = object begin let v = 1; print(\"\") end;
+begin function h() -> 1 end;
+let o = 2"), [
+            "error in function 'f': Variable 'y' is redeclared in the same block.",
+            "error in function 'f': Called function 'g' is not defined.",
+            "error: Wrong number of arguments in function call 'f', expected 1, got 2.",
+            "error: Wrong number of arguments in function call 'range', expected 2, got 1.",
+            "error: Object can only contain variables or methods.",
+            "error: Function 'h' can only be declared as top level statement.",
+            "error: Variable 'o' is redeclared.",
+        ]);
+    }
+
+    #[test]
+    fn warnings() {
+        assert_eq!(messages("\
+let x = 1;
+function f(a) -> begin
+    let x = a;
+    let unused = 2;
+    let _ignored = 3;
+    begin let a = 1; a end;
+    x
+end;
+let o = object begin
+    let v = 1;
+    function m() -> begin let v = this.v; v end
+end;
+for i in range(0, 3) do let z = i; f(x)"), [
+            "warning in function 'f': Variable 'x' shadows an outer variable.",
+            "warning in function 'f': Variable 'a' shadows an outer variable.",
+            "warning in function 'f': Variable 'unused' is never used.",
+            "warning: Variable 'z' is never used.",
+        ]);
+    }
+
+    #[test]
+    fn types() {
+        assert_eq!(messages("\
+function add(a: int, b: int): int -> a + b;
+function flag(): bool -> 1 < 2;
+function mixed(x): int -> if x then 1 else null;
+function early(x): int -> begin if x then return true; 1 end;
+let n: int = add(1, flag());
+let b: bool = add(1, 2);
+n <- null;
+let o = object begin let v: array = 1; function m(): object -> this end;
+let unknown: int = o.v;
+-true;
+1 + false;
+true + true;
+null == 1;
+n != null"), [
+            "error in function 'early': Returned value is declared as int, got bool.",
+            "error: Parameter 'b' is declared as int, got bool.",
+            "error: Variable 'b' is declared as bool, got int.",
+            "error: Variable 'n' is declared as int, got null.",
+            "error: Field 'v' is declared as array, got int.",
+            "error: Operator '-' can't be used on bool.",
+            "error: Operator '+' can't be used on int and bool.",
+            "error: Operator '+' can't be used on bool and bool.",
+        ]);
+    }
+
+    #[test]
+    fn branches_declare_separately() {
+        assert_eq!(messages("\
+let c = true;
+if c then let g = 1 else let g = 2;
+begin if c then let x = 1 else let x = 2; x end;
+function f() -> begin if c then let y = 1 else let z = 2; y + z end;
+f()"), Vec::<String>::new());
+        assert_eq!(messages("let c = true; begin if c then let x = 1 else let x = 2; let x = 3; x end; let g = 1; if c then let g = 2"), [
+            "error: Variable 'x' is redeclared in the same block.",
+            "error: Variable 'g' is redeclared.",
+        ]);
+    }
+
+    #[test]
+    fn nested_imports() {
+        let dir = std::env::temp_dir().join(format!("fml_checker_imports_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.fml"), "function lib() -> 5").unwrap();
+        let check_in_dir = |source: &str| check(&parse(source).unwrap(), Some(&dir.join("main.fml")));
+        assert_eq!(check_in_dir("begin import \"lib.fml\"; lib() end"), []);
+        assert_eq!(check_in_dir("function f() -> begin import \"lib.fml\"; lib(1) end; f()").len(), 1);
+        // Functions of a module that can't be read are unknown.
+        assert_eq!(check_in_dir("if false then import \"missing.fml\"; missing()"), []);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn user_functions_hide_builtins() {
+        assert_eq!(messages("function range(n) -> n; range(3)"), Vec::<String>::new());
+    }
+}
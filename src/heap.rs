@@ -1,11 +1,81 @@
-
 use core::ffi::c_void;
 use crate::interpreter::Value;
-use std::collections::{HashMap};
 
-#[derive(Copy, Clone)]
-pub struct Pointer{
-    data: *mut Value,
+/// Tagged word representing an FML value.
+/// Ints, booleans and null are stored directly in the word (immediates),
+/// everything else lives on the heap: arrays, objects, dicts, errors,
+/// floats, big ints and strings. Heap blocks are at least
+/// 16 byte aligned, so the lowest bits of a heap address are always zero
+/// and can be used as a tag.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Pointer(u64);
+
+const TAG_BITS: u32 = 3;
+const TAG_MASK: u64 = (1 << TAG_BITS) - 1;
+const TAG_REFERENCE: u64 = 0b000;
+const TAG_INT: u64 = 0b001;
+const TAG_BOOL: u64 = 0b010;
+const TAG_NULL: u64 = 0b011;
+
+/// Pointer with its tag resolved, used for matching on the kind of value.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Tagged {
+    Int(i32),
+    Boolean(bool),
+    Null,
+    Reference,
+}
+
+impl Pointer {
+    pub fn int(val: i32) -> Self {
+        Pointer(((val as u32 as u64) << TAG_BITS) | TAG_INT)
+    }
+
+    pub fn boolean(val: bool) -> Self {
+        Pointer(((val as u64) << TAG_BITS) | TAG_BOOL)
+    }
+
+    pub fn null() -> Self {
+        Pointer(TAG_NULL)
+    }
+
+    fn reference(ptr: *mut Value) -> Self {
+        let addr = ptr as u64;
+        assert!(addr & TAG_MASK == 0, "Heap value is not aligned.");
+        Pointer(addr | TAG_REFERENCE)
+    }
+
+    pub fn tagged(self) -> Tagged {
+        match self.0 & TAG_MASK {
+            TAG_INT => Tagged::Int((self.0 >> TAG_BITS) as u32 as i32),
+            TAG_BOOL => Tagged::Boolean(self.0 >> TAG_BITS != 0),
+            TAG_NULL => Tagged::Null,
+            TAG_REFERENCE => Tagged::Reference,
+            _ => panic!("Invalid pointer tag."),
+        }
+    }
+
+    pub fn as_int(self) -> Option<i32> {
+        match self.tagged() {
+            Tagged::Int(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(self) -> bool {
+        self.tagged() == Tagged::Null
+    }
+
+    pub fn is_reference(self) -> bool {
+        self.tagged() == Tagged::Reference
+    }
+
+    fn data(self) -> *mut Value {
+        if !self.is_reference() {
+            panic!("Can't dereference immediate value.");
+        }
+        self.0 as *mut Value
+    }
 }
 
 extern "C" {
@@ -15,10 +85,34 @@ extern "C" {
     fn heap_done() -> i32;
 }
 
+/// Running totals of heap allocations.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Allocated {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl std::ops::Sub for Allocated {
+    type Output = Allocated;
+
+    fn sub(self, other: Allocated) -> Allocated {
+        Allocated{count: self.count - other.count, bytes: self.bytes - other.bytes}
+    }
+}
+
+/// Allocation not yet attributed to the code that made it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Allocation {
+    /// Type name of the allocated value.
+    pub kind: &'static str,
+    pub bytes: u64,
+}
+
 pub struct Heap {
-    int_literals: HashMap<i32, Pointer>,
-    bool_literals: HashMap<bool, Pointer>,
-    unit: Pointer,
+    /// Everything allocated so far, nothing is ever freed.
+    pub allocated: Allocated,
+    /// Allocations made since the log was last drained, kept only if enabled.
+    pub log: Option<Vec<Allocation>>,
 }
 
 impl Drop for Heap {
@@ -33,28 +127,26 @@ impl Heap {
     pub fn new() -> Self {
         unsafe {
             heap_init();
-            let mut heap = Heap {
-                int_literals: HashMap::new(),
-                bool_literals: HashMap::new(),
-                unit: Pointer{data: 0 as *mut Value},
-                };
-            heap.unit = heap.alloc(Value::Unit);
-            heap
         }
+        Heap {allocated: Allocated::default(), log: None}
     }
 
     pub fn alloc(&mut self, value: Value) -> Pointer {
+        let bytes = std::mem::size_of_val(&value);
+        self.count(value.type_name(), bytes);
         unsafe {
-            let ptr = heap_alloc(std::mem::size_of_val(&value).try_into().unwrap()) as *mut Value;
+            let ptr = heap_alloc(bytes.try_into().unwrap()) as *mut Value;
             if ptr.is_null() {
                 panic!("A null pointer was returned by alloc.")
             }
-            *ptr = value;
-            Pointer{data: ptr}
+            ptr.write(value);
+            Pointer::reference(ptr)
         }
     }
 
+    /// Allocates elements of an array.
     pub fn alloc_bytes(&mut self, bytes: usize) -> *mut Pointer {
+        self.count("array", bytes);
         unsafe {
             let ptr =heap_alloc(bytes.try_into().expect("Couldn't convert 'bytes' to 32bit integer.")) as *mut Pointer;
             if ptr.is_null() {
@@ -64,29 +156,23 @@ impl Heap {
         }
     }
 
-    pub fn deref(&self, ptr: Pointer) -> &Value {
-        unsafe {
-            &*ptr.data
+    fn count(&mut self, kind: &'static str, bytes: usize) {
+        self.allocated.count += 1;
+        self.allocated.bytes += bytes as u64;
+        if let Some(log) = &mut self.log {
+            log.push(Allocation{kind, bytes: bytes as u64});
         }
     }
 
-    pub fn deref_mut(&mut self, ptr: Pointer) -> &mut Value {
+    pub fn deref(&self, ptr: Pointer) -> &Value {
         unsafe {
-            &mut*ptr.data
+            &*ptr.data()
         }
     }
 
-    /// Returns pointer to an integer constant value on the heap.
-    /// If this int isn't on the heap, allocation will be done.
-    pub fn get_int(&mut self, val: i32) -> Pointer {
-        let int_lit = self.int_literals.get(&val);
-        match int_lit {
-            Some(ptr) => *ptr,
-            None =>  {
-                let int_ptr = self.alloc(Value::Int(val));
-                self.int_literals.insert(val, int_ptr);
-                int_ptr
-            }
+    pub fn deref_mut(&mut self, ptr: Pointer) -> &mut Value {
+        unsafe {
+            &mut*ptr.data()
         }
     }
 
@@ -98,7 +184,7 @@ impl Heap {
                 *(ptr_data.offset(pos.try_into().unwrap())) = *item;
             }
         }
-        self.alloc(Value::Array{size: size, data: ptr_data})
+        self.alloc(Value::Array{size, data: ptr_data})
     }
 
     pub fn assign_array(&mut self, array_data: *mut Pointer, index: i32, data: Pointer) {
@@ -112,19 +198,22 @@ impl Heap {
             *array_data.offset(index.try_into().unwrap())
         }
     }
-    
-    pub fn get_bool(&mut self, val: bool) -> Pointer {
-        match self.bool_literals.get(&val) {
-            Some(ptr) => *ptr,
-            None =>  {
-                let bool_ptr = self.alloc(Value::Boolean(val));
-                self.bool_literals.insert(val, bool_ptr);
-                bool_ptr
-            }
-        }
-    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    pub fn get_unit(&self) -> Pointer {
-        self.unit
+    #[test]
+    fn immediates() {
+        for val in [0, 1, -1, 42, i32::MIN, i32::MAX] {
+            assert_eq!(Pointer::int(val).tagged(), Tagged::Int(val));
+        }
+        assert_eq!(Pointer::boolean(true).tagged(), Tagged::Boolean(true));
+        assert_eq!(Pointer::boolean(false).tagged(), Tagged::Boolean(false));
+        assert_eq!(Pointer::null().tagged(), Tagged::Null);
+        assert_eq!(Pointer::int(7), Pointer::int(7));
+        assert_ne!(Pointer::int(0), Pointer::boolean(false));
+        assert_ne!(Pointer::int(0), Pointer::null());
     }
-}
\ No newline at end of file
+}
@@ -2,19 +2,147 @@
 mod interpreter;
 mod ast;
 mod heap;
+mod parser;
+mod printer;
+mod checker;
+mod optimizer;
+mod profiler;
+mod tracer;
 
 use ast::AST;
-use interpreter::interpret;
-use std::fs;
+use checker::Severity;
+use interpreter::{interpret_instrumented, read_program, Instrumentation};
+use profiler::{AllocationProfiler, Profiler};
+use tracer::{Only, Tracer};
 use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} run [--no-check] [-O0|-O1] [--profile] [--profile-folded=<file>] [--profile-allocations]", program);
+    eprintln!("           [--trace[=calls|=assignments]] [--trace-depth=<n>] <program> [-- <arguments>...]");
+    eprintln!("       {} fmt <program>", program);
+    eprintln!("       {} check <program>", program);
+    std::process::exit(1);
+}
+
+fn load(path: &Path) -> AST {
+    match read_program(path) {
+        Ok(tree) => tree,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    }
+}
 
-fn main() -> Result<(), std::io::Error> {
+/// Prints diagnostics of the program, with 'errors_only' warnings are
+/// left out. Returns true if there are errors.
+fn report(tree: &AST, path: &Path, errors_only: bool) -> bool {
+    let mut failed = false;
+    for diagnostic in checker::check(tree, Some(path)) {
+        failed |= diagnostic.severity == Severity::Error;
+        if !errors_only || diagnostic.severity == Severity::Error {
+            eprintln!("{}", diagnostic);
+        }
+    }
+    failed
+}
+
+/// Returns the tracer of the run, it's created on first use and logs to stderr.
+fn tracer(instrumentation: &mut Instrumentation) -> &mut Tracer {
+    instrumentation.tracer.get_or_insert_with(|| Tracer::new(Box::new(io::BufWriter::new(io::stderr()))))
+}
+
+fn main() {
     let args: Vec<String> = env::args().collect();
-    if args[1] != "run" {
-        ()
+    if args.len() < 3 {
+        usage(&args[0]);
+    }
+    match args[1].as_str() {
+        "run" => {
+            let mut rest = &args[2..];
+            let mut check = true;
+            let mut optimize = None;
+            let mut instrumentation = Instrumentation::default();
+            let mut folded = None;
+            while let Some(option) = rest.first().filter(|arg| arg.starts_with('-')) {
+                match option.as_str() {
+                    "--no-check" => check = false,
+                    "-O0" => optimize = Some(false),
+                    "-O1" => optimize = Some(true),
+                    "--profile" => instrumentation.profiler = Some(Profiler::new()),
+                    "--profile-allocations" => instrumentation.allocations = Some(AllocationProfiler::new()),
+                    "--trace" | "--trace=calls" | "--trace=assignments" => {
+                        tracer(&mut instrumentation).only = match option.as_str() {
+                            "--trace=calls" => Some(Only::Calls),
+                            "--trace=assignments" => Some(Only::Assignments),
+                            _ => None,
+                        };
+                    }
+                    _ if option.starts_with("--trace-depth=") => match option["--trace-depth=".len()..].parse() {
+                        Ok(depth) => tracer(&mut instrumentation).max_depth = Some(depth),
+                        Err(_) => usage(&args[0]),
+                    },
+                    _ if option.starts_with("--profile-folded=") => {
+                        instrumentation.profiler = Some(Profiler::new());
+                        folded = Some(&option["--profile-folded=".len()..]);
+                    }
+                    _ => usage(&args[0]),
+                }
+                rest = &rest[1..];
+            }
+            let Some(path) = rest.first().map(Path::new) else {
+                usage(&args[0]);
+            };
+            let program_args = match rest.get(1).map(String::as_str) {
+                Some("--") => &rest[2..],
+                _ => &rest[1..],
+            };
+            let mut tree = load(path);
+            if check && report(&tree, path, true) {
+                std::process::exit(1);
+            }
+            // Traces and profiles show the program as written, unless -O1 is given.
+            let instrumented = instrumentation.profiler.is_some() || instrumentation.allocations.is_some() || instrumentation.tracer.is_some();
+            if optimize.unwrap_or(!instrumented) {
+                tree = optimizer::optimize(&tree);
+            }
+            let mut output = io::BufWriter::new(io::stdout().lock());
+            let result = interpret_instrumented(&tree, Some(path), program_args, Box::new(io::stdin().lock()), &mut output, &mut instrumentation);
+            if let Some(profiler) = instrumentation.profiler {
+                // Profiles are written even if the program failed.
+                if let Err(err) = profiler.report(&mut io::stderr()) {
+                    eprintln!("Can't write profile: {}", err);
+                }
+                if let Some(folded) = folded {
+                    let written = fs::File::create(folded).and_then(|mut file| profiler.write_folded(&mut file));
+                    if let Err(err) = written {
+                        eprintln!("Can't write '{}': {}", folded, err);
+                    }
+                }
+            }
+            if let Some(allocations) = instrumentation.allocations {
+                if let Err(err) = allocations.report(&mut io::stderr()) {
+                    eprintln!("Can't write profile: {}", err);
+                }
+            }
+            match result {
+                Ok(code) => std::process::exit(code),
+                Err(message) => {
+                    eprintln!("{}", message);
+                    std::process::exit(1);
+                }
+            }
+        }
+        "fmt" => print!("{}", printer::format(&load(Path::new(&args[2])))),
+        "check" => {
+            let path = Path::new(&args[2]);
+            if report(&load(path), path, false) {
+                std::process::exit(1);
+            }
+        }
+        _ => usage(&args[0]),
     }
-    let program = fs::read_to_string(&args[2])?;
-    let tree: AST = serde_json::from_str(&program).unwrap();
-    interpret(tree);
-    Ok(())
 }
@@ -0,0 +1,287 @@
+use crate::ast::AST;
+use std::rc::Rc;
+
+/// Folds int operator applied to literals. None if the result doesn't fit
+/// in 32 bits or the operation fails, both are left to the runtime.
+fn fold_int(left: i32, name: &str, right: i32) -> Option<AST> {
+    let val = match name {
+        "+" => left.checked_add(right),
+        "-" => left.checked_sub(right),
+        "*" => left.checked_mul(right),
+        "/" => left.checked_div(right),
+        "%" => left.checked_rem(right),
+        "&" => Some(left & right),
+        "|" => Some(left | right),
+        "^" => Some(left ^ right),
+        "<<" => (0..32).contains(&right).then(|| i32::try_from(i64::from(left) << right).ok()).flatten(),
+        ">>" => (0..32).contains(&right).then(|| left >> right),
+        ">>>" => (0..32).contains(&right).then(|| i32::try_from(left as u32 >> right).ok()).flatten(),
+        "==" => return Some(AST::Boolean(left == right)),
+        "!=" => return Some(AST::Boolean(left != right)),
+        "<" => return Some(AST::Boolean(left < right)),
+        "<=" => return Some(AST::Boolean(left <= right)),
+        ">" => return Some(AST::Boolean(left > right)),
+        ">=" => return Some(AST::Boolean(left >= right)),
+        _ => None,
+    };
+    val.map(AST::Integer)
+}
+
+fn fold_bool(left: bool, name: &str, right: bool) -> Option<AST> {
+    match name {
+        "&" => Some(AST::Boolean(left && right)),
+        "|" => Some(AST::Boolean(left || right)),
+        "==" => Some(AST::Boolean(left == right)),
+        "!=" => Some(AST::Boolean(left != right)),
+        _ => None,
+    }
+}
+
+fn fold_unary(operand: &AST, name: &str) -> Option<AST> {
+    match (operand, name) {
+        (AST::Integer(val), "-") => val.checked_neg().map(AST::Integer),
+        (AST::Integer(val), "~") => Some(AST::Integer(!val)),
+        (AST::Boolean(val), "!") => Some(AST::Boolean(!val)),
+        _ => None,
+    }
+}
+
+/// Truthiness of a literal, None if the value isn't known.
+fn truthy(ast: &AST) -> Option<bool> {
+    match ast {
+        AST::Null | AST::Boolean(false) => Some(false),
+        AST::Boolean(true) | AST::Integer(_) | AST::BigInteger(_) | AST::Float(_) | AST::String(_) => Some(true),
+        _ => None,
+    }
+}
+
+/// Returns true if the expression never finishes normally, so anything
+/// following it in a block is unreachable.
+fn diverges(ast: &AST) -> bool {
+    match ast {
+        AST::Return{..} | AST::Throw{..} | AST::Break | AST::Continue => true,
+        AST::Block(exprs) => exprs.iter().any(|expr| diverges(expr)),
+        AST::Conditional{condition, consequent, alternative} => diverges(condition) || (diverges(consequent) && diverges(alternative)),
+        _ => false,
+    }
+}
+
+/// Returns true if evaluation of the expression can add a variable to
+/// the environment it is evaluated in. Blocks, loops and handlers have
+/// their own environments.
+fn declares(ast: &AST) -> bool {
+    let any = |exprs: &[Box<AST>]| exprs.iter().any(|expr| declares(expr));
+    match ast {
+        AST::Variable{..} | AST::Import{..} | AST::Top(_) => true,
+        AST::Integer(_) | AST::BigInteger(_) | AST::Float(_) | AST::String(_) | AST::Boolean(_) | AST::Null
+        | AST::AccessVariable{..} | AST::Break | AST::Continue | AST::Block(_) | AST::Loop{..} | AST::Function{..} => false,
+        AST::Object{extends, members} => declares(extends) || members.iter().any(|member| match &**member {
+            AST::Variable{name:_, annotation:_, value} => declares(value),
+            _ => false,
+        }),
+        AST::For{name:_, iterable, body:_} => declares(iterable),
+        AST::Try{body, name:_, handler:_, finally} => declares(body) || declares(finally),
+        AST::Array{size: first, value: second} | AST::AccessArray{array: first, index: second}
+        | AST::AssignField{object: first, field:_, value: second} => declares(first) || declares(second),
+        AST::AccessField{object: value, field:_} | AST::AssignVariable{name:_, value} | AST::Throw{value} | AST::Return{value} => declares(value),
+        AST::AssignArray{array, index, value} => declares(array) || declares(index) || declares(value),
+        AST::CallFunction{name:_, arguments} | AST::Print{format:_, arguments} => any(arguments),
+        AST::CallMethod{object, name:_, arguments} => declares(object) || any(arguments),
+        AST::Conditional{condition, consequent, alternative} => declares(condition) || declares(consequent) || declares(alternative),
+    }
+}
+
+/// Optimizes expressions of a block. Nested blocks without declarations
+/// are spliced in, values that are dropped are left out and so is
+/// everything after an expression that never finishes.
+#[allow(clippy::vec_box)]
+fn optimize_sequence(exprs: &[Box<AST>]) -> Vec<Box<AST>> {
+    let mut result: Vec<Box<AST>> = Vec::new();
+    for (index, expr) in exprs.iter().enumerate() {
+        let last = index == exprs.len() - 1;
+        match optimize(expr) {
+            AST::Block(inner) if !inner.is_empty() && !inner.iter().any(|expr| declares(expr)) => result.extend(inner),
+            AST::Integer(_) | AST::Boolean(_) | AST::Null if !last => (),
+            expr => result.push(Box::new(expr)),
+        }
+        if result.last().is_some_and(|expr| diverges(expr)) {
+            break;
+        }
+    }
+    result
+}
+
+#[allow(clippy::vec_box)]
+fn optimize_all(exprs: &[Box<AST>]) -> Vec<Box<AST>> {
+    exprs.iter().map(|expr| Box::new(optimize(expr))).collect()
+}
+
+/// Returns simplified tree that behaves the same as 'ast'. Operators on
+/// int and bool literals are folded, conditionals and loops with constant
+/// conditions are pruned, blocks are flattened and unreachable code is
+/// removed.
+pub fn optimize(ast: &AST) -> AST {
+    let opt = |ast: &AST| Box::new(optimize(ast));
+    match ast {
+        AST::Integer(_) | AST::BigInteger(_) | AST::Float(_) | AST::String(_) | AST::Boolean(_) | AST::Null
+        | AST::AccessVariable{..} | AST::Import{..} | AST::Break | AST::Continue => ast.clone(),
+
+        AST::Variable{name, annotation, value} => AST::Variable{name: name.clone(), annotation: *annotation, value: opt(value)},
+        AST::Array{size, value} => AST::Array{size: opt(size), value: opt(value)},
+        AST::Object{extends, members} => AST::Object{extends: opt(extends), members: Rc::new(optimize_all(members))},
+
+        AST::AccessField{object, field} => AST::AccessField{object: opt(object), field: field.clone()},
+        AST::AccessArray{array, index} => AST::AccessArray{array: opt(array), index: opt(index)},
+
+        AST::AssignVariable{name, value} => AST::AssignVariable{name: name.clone(), value: opt(value)},
+        AST::AssignField{object, field, value} => AST::AssignField{object: opt(object), field: field.clone(), value: opt(value)},
+        AST::AssignArray{array, index, value} => AST::AssignArray{array: opt(array), index: opt(index), value: opt(value)},
+
+        AST::Function{name, parameters, signature, body} => {
+            AST::Function{name: name.clone(), parameters: parameters.clone(), signature: signature.clone(), body: opt(body)}
+        }
+        AST::CallFunction{name, arguments} => AST::CallFunction{name: name.clone(), arguments: optimize_all(arguments)},
+        AST::CallMethod{object, name, arguments} => {
+            let object = opt(object);
+            let arguments = optimize_all(arguments);
+            let folded = match (&*object, arguments.as_slice()) {
+                (operand, []) => fold_unary(operand, name),
+                (AST::Integer(left), [right]) => match **right {
+                    AST::Integer(right) => fold_int(*left, name, right),
+                    _ => None,
+                },
+                (AST::Boolean(left), [right]) => match **right {
+                    AST::Boolean(right) => fold_bool(*left, name, right),
+                    _ => None,
+                },
+                _ => None,
+            };
+            folded.unwrap_or_else(|| AST::CallMethod{object, name: name.clone(), arguments})
+        }
+
+        AST::Top(stmts) => {
+            let mut result = optimize_all(stmts);
+            if let Some(end) = result.iter().position(|stmt| diverges(stmt)) {
+                result.truncate(end + 1);
+            }
+            AST::Top(result)
+        }
+        AST::Block(exprs) => {
+            let mut exprs = optimize_sequence(exprs);
+            if exprs.iter().any(|expr| declares(expr)) {
+                return AST::Block(exprs);
+            }
+            match exprs.len() {
+                0 => AST::Null,
+                1 => *exprs.pop().unwrap(),
+                _ => AST::Block(exprs),
+            }
+        }
+        AST::Loop{condition, body} => {
+            let condition = opt(condition);
+            match truthy(&condition) {
+                Some(false) => AST::Null,
+                _ => AST::Loop{condition, body: opt(body)},
+            }
+        }
+        AST::For{name, iterable, body} => AST::For{name: name.clone(), iterable: opt(iterable), body: opt(body)},
+        AST::Conditional{condition, consequent, alternative} => {
+            let condition = opt(condition);
+            match truthy(&condition) {
+                Some(true) => optimize(consequent),
+                Some(false) => optimize(alternative),
+                None => AST::Conditional{condition, consequent: opt(consequent), alternative: opt(alternative)},
+            }
+        }
+
+        AST::Print{format, arguments} => AST::Print{format: format.clone(), arguments: optimize_all(arguments)},
+
+        AST::Throw{value} => AST::Throw{value: opt(value)},
+        AST::Try{body, name, handler, finally} => AST::Try{body: opt(body), name: name.clone(), handler: opt(handler), finally: opt(finally)},
+
+        AST::Return{value} => AST::Return{value: opt(value)},
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interpreter::{interpret, read_program};
+    use crate::parser::parse;
+    use crate::printer::format;
+    use serial_test::serial;
+    use std::io;
+    use std::path::Path;
+
+    fn optimized(source: &str) -> String {
+        format(&optimize(&parse(source).unwrap()))
+    }
+
+    /// Returns exit code or error and the output of the program.
+    fn run(ast: &AST, path: Option<&Path>) -> (Result<i32, String>, String) {
+        let mut output = Vec::new();
+        let result = interpret(ast, path, &[], Box::new(io::empty()), &mut output);
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn folding() {
+        assert_eq!(optimized("let x = (4 - 0) + 1 * 2; x"), "let x = 6;\nx\n");
+        assert_eq!(optimized("1 < 2 & !false; -(5); ~0; 1 >>> 1"), "true;\n-5;\n-1;\n0\n");
+        // Overflow and errors are left for the runtime.
+        assert_eq!(optimized("2147483647 + 1; 1 / 0; 1 << 40; x + (1 + 1)"), "2147483647 + 1;\n1 / 0;\n1 << 40;\nx + 2\n");
+    }
+
+    #[test]
+    fn pruning() {
+        assert_eq!(optimized("if 1 < 2 then a() else b(); if null then c(); while false do d(); if x then 1 else 2"),
+            "a();\nnull;\nnull;\nif x then 1\nelse 2\n");
+        assert_eq!(optimized("function f(x) -> begin begin g(x); begin h() end end; 1; x end"),
+            "function f(x) ->\nbegin\n    g(x);\n    h();\n    x\nend\n");
+        // Blocks with declarations keep their own environments.
+        assert_eq!(optimized("begin let x = 1; begin let x = 2; x end; begin if c then let y = 1 else 2 end end"),
+            "begin\n    let x = 1;\n    begin\n        let x = 2;\n        x\n    end;\n    begin\n        if c then let y = 1\n        else 2\n    end\nend\n");
+        assert_eq!(optimized("function f(x) -> begin g(); return x; h() end; while c do begin break; i() end; exit(1); j()"),
+            "function f(x) ->\nbegin\n    g();\n    return x\nend;\nwhile c do break;\nexit(1);\nj()\n");
+        assert_eq!(optimized("begin f() end; begin end; throw 1; g()"), "f();\nnull;\nthrow 1\n");
+    }
+
+    #[test]
+    #[serial]
+    fn folding_matches_runtime() {
+        let values = [0, 1, -1, 7, -8, 31, 32, i32::MAX, i32::MIN];
+        let operators = ["+", "-", "*", "/", "%", "&", "|", "^", "<<", ">>", ">>>", "==", "!=", "<", "<=", ">", ">="];
+        let mut source = String::new();
+        for left in values {
+            for right in values {
+                for operator in operators {
+                    // Huge shifts are computed by the runtime as big ints.
+                    if operator.contains(['<', '>']) && operator.len() > 1 && operator != "<=" && operator != ">=" && right > 32 {
+                        continue;
+                    }
+                    source += &format!("try print(\"~ \", {} {} {}) catch e -> print(\"error \");\n", left, operator, right);
+                }
+            }
+            source += &format!("print(\"~ ~\\n\", -({}), ~({}));\n", left, left);
+        }
+        for left in [true, false] {
+            for right in [true, false] {
+                source += &format!("print(\"~ ~ ~ ~ ~\\n\", {0} & {1}, {0} | {1}, {0} == {1}, {0} != {1}, !{0});\n", left, right);
+            }
+        }
+        let ast = parse(&source).unwrap();
+        assert_eq!(run(&optimize(&ast), None), run(&ast, None));
+    }
+
+    #[test]
+    #[serial]
+    fn examples_behave_the_same() {
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "fml") {
+                let ast = read_program(&path).unwrap();
+                assert_eq!(run(&optimize(&ast), Some(&path)), run(&ast, Some(&path)), "{}", path.display());
+            }
+        }
+    }
+}
@@ -0,0 +1,614 @@
+use crate::ast::{AST, Signature, Type};
+use std::rc::Rc;
+
+/// Words that can't be used as names of variables or functions.
+const KEYWORDS: [&str; 27] = [
+    "let", "function", "object", "extends", "begin", "end", "if", "then", "else", "while", "do",
+    "array", "print", "true", "false", "null", "for", "in", "try", "catch", "finally", "throw",
+    "return", "break", "continue", "import", "as",
+];
+
+/// Symbols ordered so that the longest match is tried first.
+const SYMBOLS: [&str; 30] = [
+    ">>>", "<<", ">>", "<-", "->", "==", "!=", "<=", ">=",
+    "+", "-", "*", "/", "%", "<", ">", "&", "|", "^", "~", "!", "(", ")", "[", "]", ",", ";", ".", "=", ":",
+];
+
+/// Binary operators from the lowest precedence, all of them are left associative.
+pub const BINARY_LEVELS: [&[&str]; 8] = [
+    &["|"], &["^"], &["&"], &["==", "!="], &["<", "<=", ">", ">="], &["<<", ">>", ">>>"], &["+", "-"], &["*", "/", "%"],
+];
+
+pub const UNARY_OPERATORS: [&str; 3] = ["-", "!", "~"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// Digits of a non-negative integer, it may not fit in 32 bits.
+    Integer(String),
+    Float(f64),
+    /// Contents of a string literal with escapes kept as written.
+    Str(String),
+    Ident(String),
+    Symbol(&'static str),
+    Eof,
+}
+
+/// Splits the source to tokens paired with their line numbers.
+fn lex(source: &str) -> Result<Vec<(Token, usize)>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        let rest = &chars[pos..];
+        if c == '\n' {
+            line += 1;
+            pos += 1;
+        } else if c.is_whitespace() {
+            pos += 1;
+        } else if rest.starts_with(&['/', '/']) {
+            while pos < chars.len() && chars[pos] != '\n' {
+                pos += 1;
+            }
+        } else if rest.starts_with(&['/', '*']) {
+            let start = line;
+            pos += 2;
+            while pos < chars.len() && !chars[pos..].starts_with(&['*', '/']) {
+                line += (chars[pos] == '\n') as usize;
+                pos += 1;
+            }
+            if pos == chars.len() {
+                return Err(format!("Line {}: Unterminated comment.", start));
+            }
+            pos += 2;
+        } else if c.is_ascii_digit() {
+            let start = pos;
+            let digits = |pos: &mut usize| while *pos < chars.len() && chars[*pos].is_ascii_digit() { *pos += 1 };
+            digits(&mut pos);
+            let mut float = false;
+            if pos + 1 < chars.len() && chars[pos] == '.' && chars[pos + 1].is_ascii_digit() {
+                float = true;
+                pos += 1;
+                digits(&mut pos);
+            }
+            if pos < chars.len() && (chars[pos] == 'e' || chars[pos] == 'E') {
+                let sign = (pos + 1 < chars.len() && (chars[pos + 1] == '+' || chars[pos + 1] == '-')) as usize;
+                if pos + 1 + sign < chars.len() && chars[pos + 1 + sign].is_ascii_digit() {
+                    float = true;
+                    pos += 1 + sign;
+                    digits(&mut pos);
+                }
+            }
+            let text: String = chars[start..pos].iter().collect();
+            let token = match float {
+                true => Token::Float(text.parse().map_err(|_| format!("Line {}: Invalid float '{}'.", line, text))?),
+                false => Token::Integer(text),
+            };
+            tokens.push((token, line));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '_') {
+                pos += 1;
+            }
+            tokens.push((Token::Ident(chars[start..pos].iter().collect()), line));
+        } else if c == '"' {
+            let start = line;
+            pos += 1;
+            let mut text = String::new();
+            while pos < chars.len() && chars[pos] != '"' {
+                if chars[pos] == '\\' && pos + 1 < chars.len() {
+                    text.push(chars[pos]);
+                    pos += 1;
+                }
+                line += (chars[pos] == '\n') as usize;
+                text.push(chars[pos]);
+                pos += 1;
+            }
+            if pos == chars.len() {
+                return Err(format!("Line {}: Unterminated string.", start));
+            }
+            pos += 1;
+            tokens.push((Token::Str(text), start));
+        } else {
+            let symbol = SYMBOLS.iter().find(|symbol| symbol.chars().enumerate().all(|(i, c)| rest.get(i) == Some(&c)));
+            match symbol {
+                Some(symbol) => {
+                    tokens.push((Token::Symbol(symbol), line));
+                    pos += symbol.len();
+                }
+                None => return Err(format!("Line {}: Unexpected character '{}'.", line, c)),
+            }
+        }
+    }
+    tokens.push((Token::Eof, line));
+    Ok(tokens)
+}
+
+/// Resolves escapes of a string literal.
+fn unescape(text: &str) -> String {
+    let mut res = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => res.push('\n'),
+            Some('t') => res.push('\t'),
+            Some('r') => res.push('\r'),
+            Some(c @ ('\\' | '"')) => res.push(c),
+            Some(c) => {
+                res.push('\\');
+                res.push(c);
+            }
+            None => res.push('\\'),
+        }
+    }
+    res
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn peek_next(&self) -> &Token {
+        &self.tokens[(self.pos + 1).min(self.tokens.len() - 1)].0
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].0.clone();
+        self.pos = (self.pos + 1).min(self.tokens.len() - 1);
+        token
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        let (token, line) = &self.tokens[self.pos];
+        let found = match token {
+            Token::Integer(digits) => digits.clone(),
+            Token::Float(val) => val.to_string(),
+            Token::Str(text) => format!("\"{}\"", text),
+            Token::Ident(name) => name.clone(),
+            Token::Symbol(symbol) => symbol.to_string(),
+            Token::Eof => String::from("end of file"),
+        };
+        Err(format!("Line {}: {}, found '{}'.", line, message, found))
+    }
+
+    /// Returns true if the next token is the keyword or symbol 'word'.
+    fn at(&self, word: &str) -> bool {
+        match self.peek() {
+            Token::Ident(name) => name == word,
+            Token::Symbol(symbol) => *symbol == word,
+            _ => false,
+        }
+    }
+
+    fn eat(&mut self, word: &str) -> bool {
+        let found = self.at(word);
+        if found {
+            self.next();
+        }
+        found
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), String> {
+        match self.eat(word) {
+            true => Ok(()),
+            false => self.error(&format!("Expected '{}'", word)),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Token::Ident(name) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.next();
+                Ok(name)
+            }
+            _ => self.error("Expected a name"),
+        }
+    }
+
+    /// Name of a function or method, operators can be used as well.
+    fn function_name(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Token::Symbol(symbol) if BINARY_LEVELS.iter().any(|level| level.contains(symbol)) || UNARY_OPERATORS.contains(symbol) => {
+                let name = symbol.to_string();
+                self.next();
+                Ok(name)
+            }
+            _ => self.name(),
+        }
+    }
+
+    /// Parses optional type annotation ': type'.
+    fn annotation(&mut self) -> Result<Option<Type>, String> {
+        if !self.eat(":") {
+            return Ok(None);
+        }
+        let annotation = match self.peek() {
+            Token::Ident(name) => Type::from_name(name),
+            _ => None,
+        };
+        match annotation {
+            Some(annotation) => {
+                self.next();
+                Ok(Some(annotation))
+            }
+            None => self.error("Expected a type"),
+        }
+    }
+
+    /// Parses expressions separated by ';' until 'end' or end of file,
+    /// a trailing ';' is allowed.
+    #[allow(clippy::vec_box)]
+    fn sequence(&mut self) -> Result<Vec<Box<AST>>, String> {
+        let mut exprs = Vec::new();
+        while !self.at("end") && *self.peek() != Token::Eof {
+            exprs.push(self.expr()?);
+            if !self.eat(";") {
+                break;
+            }
+        }
+        Ok(exprs)
+    }
+
+    #[allow(clippy::vec_box)]
+    fn arguments(&mut self) -> Result<Vec<Box<AST>>, String> {
+        self.expect("(")?;
+        let mut arguments = Vec::new();
+        while !self.at(")") {
+            arguments.push(self.expr()?);
+            if !self.eat(",") {
+                break;
+            }
+        }
+        self.expect(")")?;
+        Ok(arguments)
+    }
+
+    fn expr(&mut self) -> Result<Box<AST>, String> {
+        let ast = if self.eat("let") {
+            let name = self.name()?;
+            let annotation = self.annotation()?;
+            self.expect("=")?;
+            AST::Variable{name, annotation, value: self.expr()?}
+        } else if self.eat("function") {
+            let name = self.function_name()?;
+            self.expect("(")?;
+            let mut parameters = Vec::new();
+            let mut signature = Signature::default();
+            while !self.at(")") {
+                parameters.push(self.name()?);
+                signature.parameters.push(self.annotation()?);
+                if !self.eat(",") {
+                    break;
+                }
+            }
+            self.expect(")")?;
+            signature.returns = self.annotation()?;
+            if signature.parameters.iter().all(Option::is_none) {
+                signature.parameters.clear();
+            }
+            self.expect("->")?;
+            AST::Function{name, parameters, signature, body: self.expr()?}
+        } else if self.eat("if") {
+            let condition = self.expr()?;
+            self.expect("then")?;
+            let consequent = self.expr()?;
+            let alternative = if self.eat("else") { self.expr()? } else { AST::Null.into_boxed() };
+            AST::Conditional{condition, consequent, alternative}
+        } else if self.eat("while") {
+            let condition = self.expr()?;
+            self.expect("do")?;
+            AST::Loop{condition, body: self.expr()?}
+        } else if self.eat("for") {
+            let name = self.name()?;
+            self.expect("in")?;
+            let iterable = self.expr()?;
+            self.expect("do")?;
+            AST::For{name, iterable, body: self.expr()?}
+        } else if self.eat("try") {
+            let body = self.expr()?;
+            self.expect("catch")?;
+            let name = self.name()?;
+            self.expect("->")?;
+            let handler = self.expr()?;
+            let finally = if self.eat("finally") { self.expr()? } else { AST::Null.into_boxed() };
+            AST::Try{body, name, handler, finally}
+        } else if self.eat("throw") {
+            AST::Throw{value: self.expr()?}
+        } else if self.eat("return") {
+            let value = match self.at(";") || self.at("end") || *self.peek() == Token::Eof {
+                true => AST::Null.into_boxed(),
+                false => self.expr()?,
+            };
+            AST::Return{value}
+        } else if self.eat("break") {
+            AST::Break
+        } else if self.eat("continue") {
+            AST::Continue
+        } else if self.eat("import") {
+            let path = match self.next() {
+                Token::Str(path) => unescape(&path),
+                _ => {
+                    self.pos -= 1;
+                    return self.error("Expected path of the import");
+                }
+            };
+            let name = if self.eat("as") { Some(self.name()?) } else { None };
+            AST::Import{path, name}
+        } else {
+            return self.assignment();
+        };
+        Ok(ast.into_boxed())
+    }
+
+    fn assignment(&mut self) -> Result<Box<AST>, String> {
+        let target = self.binary(0)?;
+        if !self.at("<-") {
+            return Ok(target);
+        }
+        let line = self.tokens[self.pos].1;
+        self.next();
+        let value = self.expr()?;
+        let ast = match *target {
+            AST::AccessVariable{name} => AST::AssignVariable{name, value},
+            AST::AccessField{object, field} => AST::AssignField{object, field, value},
+            AST::AccessArray{array, index} => AST::AssignArray{array, index, value},
+            _ => return Err(format!("Line {}: Only variables, fields and array elements can be assigned.", line)),
+        };
+        Ok(ast.into_boxed())
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Box<AST>, String> {
+        if level == BINARY_LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Token::Symbol(symbol) = self.peek() {
+            if !BINARY_LEVELS[level].contains(symbol) {
+                break;
+            }
+            let name = symbol.to_string();
+            self.next();
+            let right = self.binary(level + 1)?;
+            left = AST::CallMethod{object: left, name, arguments: [right].to_vec()}.into_boxed();
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Box<AST>, String> {
+        let operator = match self.peek() {
+            Token::Symbol("-") if matches!(self.peek_next(), Token::Integer(_) | Token::Float(_)) => None,
+            Token::Symbol(symbol) if UNARY_OPERATORS.contains(symbol) => Some(symbol.to_string()),
+            _ => None,
+        };
+        match operator {
+            Some(name) => {
+                self.next();
+                let object = self.unary()?;
+                Ok(AST::CallMethod{object, name, arguments: Vec::new()}.into_boxed())
+            }
+            None => self.postfix(),
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Box<AST>, String> {
+        let mut ast = self.primary()?;
+        loop {
+            if self.eat(".") {
+                let name = self.function_name()?;
+                ast = match self.at("(") {
+                    true => AST::CallMethod{object: ast, name, arguments: self.arguments()?},
+                    false => AST::AccessField{object: ast, field: name},
+                }.into_boxed();
+            } else if self.eat("[") {
+                let index = self.expr()?;
+                self.expect("]")?;
+                ast = AST::AccessArray{array: ast, index}.into_boxed();
+            } else {
+                return Ok(ast);
+            }
+        }
+    }
+
+    fn number(&mut self, negative: bool) -> Result<AST, String> {
+        let sign = if negative { "-" } else { "" };
+        match self.next() {
+            Token::Integer(digits) => {
+                let digits = format!("{}{}", sign, digits);
+                Ok(match digits.parse() {
+                    Ok(val) => AST::Integer(val),
+                    Err(_) => AST::BigInteger(digits),
+                })
+            }
+            Token::Float(val) => Ok(AST::Float(if negative { -val } else { val })),
+            _ => unreachable!(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Box<AST>, String> {
+        let ast = match self.peek().clone() {
+            Token::Integer(_) | Token::Float(_) => self.number(false)?,
+            Token::Symbol("-") => {
+                self.next();
+                self.number(true)?
+            }
+            Token::Str(text) => {
+                self.next();
+                AST::String(unescape(&text))
+            }
+            Token::Symbol("(") => {
+                self.next();
+                let ast = self.expr()?;
+                self.expect(")")?;
+                return Ok(ast);
+            }
+            Token::Ident(word) => match word.as_str() {
+                "true" | "false" => {
+                    self.next();
+                    AST::Boolean(word == "true")
+                }
+                "null" => {
+                    self.next();
+                    AST::Null
+                }
+                "begin" => {
+                    self.next();
+                    let exprs = self.sequence()?;
+                    self.expect("end")?;
+                    AST::Block(exprs)
+                }
+                "array" => {
+                    self.next();
+                    self.expect("(")?;
+                    let size = self.expr()?;
+                    self.expect(",")?;
+                    let value = self.expr()?;
+                    self.expect(")")?;
+                    AST::Array{size, value}
+                }
+                "print" => {
+                    self.next();
+                    self.expect("(")?;
+                    let format = match self.next() {
+                        Token::Str(format) => format,
+                        _ => {
+                            self.pos -= 1;
+                            return self.error("Expected format string");
+                        }
+                    };
+                    let mut arguments = Vec::new();
+                    while self.eat(",") {
+                        arguments.push(self.expr()?);
+                    }
+                    self.expect(")")?;
+                    AST::Print{format, arguments}
+                }
+                "object" => {
+                    self.next();
+                    let extends = if self.eat("extends") { self.binary(0)? } else { AST::Null.into_boxed() };
+                    let members = match self.eat("begin") {
+                        true => {
+                            let members = self.sequence()?;
+                            self.expect("end")?;
+                            members
+                        }
+                        false => [self.expr()?].to_vec(),
+                    };
+                    AST::Object{extends, members: Rc::new(members)}
+                }
+                _ => {
+                    let name = self.name()?;
+                    match self.at("(") {
+                        true => AST::CallFunction{name, arguments: self.arguments()?},
+                        false => AST::AccessVariable{name},
+                    }
+                }
+            },
+            _ => return self.error("Expected an expression"),
+        };
+        Ok(ast.into_boxed())
+    }
+}
+
+/// Parses FML source to the same tree the external parser produces.
+pub fn parse(source: &str) -> Result<AST, String> {
+    let mut parser = Parser{tokens: lex(source)?, pos: 0};
+    let exprs = parser.sequence()?;
+    if *parser.peek() != Token::Eof {
+        return parser.error("Expected ';' or end of file");
+    }
+    Ok(AST::Top(exprs))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interpreter::read_program;
+
+    /// Compares every example with the tree of the external parser. It's run
+    /// when PARSER is set, otherwise the checked in JSON of the example is used.
+    #[test]
+    fn matches_external_parser() {
+        let dir = std::env::temp_dir().join(format!("fml_parser_examples_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let external = std::env::var("PARSER").is_ok();
+        let mut compared = 0;
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "fml") {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            let expected = if external {
+                // The external parser writes its JSON next to the source.
+                let copy = dir.join(path.file_name().unwrap());
+                std::fs::write(&copy, &source).unwrap();
+                read_program(&copy).unwrap()
+            } else {
+                match std::fs::read_to_string(path.with_extension("fml.json")) {
+                    Ok(json) => serde_json::from_str(&json).unwrap(),
+                    Err(_) => continue,
+                }
+            };
+            assert_eq!(parse(&source).unwrap(), expected, "{}", path.display());
+            compared += 1;
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(compared >= 3, "{}", compared);
+    }
+
+    #[test]
+    fn precedence() {
+        let op = |left: Box<AST>, name: &str, right: Box<AST>| AST::CallMethod{object: left, name: String::from(name), arguments: [right].to_vec()}.into_boxed();
+        let var = |name: &str| AST::AccessVariable{name: String::from(name)}.into_boxed();
+        let int = |val: i32| AST::Integer(val).into_boxed();
+        let top = |ast: Box<AST>| AST::Top([ast].to_vec());
+
+        assert_eq!(parse("a | b & c == 1 + 2 * 3").unwrap(), top(op(var("a"), "|", op(var("b"), "&", op(var("c"), "==", op(int(1), "+", op(int(2), "*", int(3))))))));
+        assert_eq!(parse("a - b - c").unwrap(), top(op(op(var("a"), "-", var("b")), "-", var("c"))));
+        assert_eq!(parse("1 << 2 + 3 < 4").unwrap(), top(op(op(int(1), "<<", op(int(2), "+", int(3))), "<", int(4))));
+        assert_eq!(parse("a-1").unwrap(), top(op(var("a"), "-", int(1))));
+        assert_eq!(parse("-1").unwrap(), top(int(-1)));
+        assert_eq!(parse("-2147483648").unwrap(), top(int(i32::MIN)));
+        assert_eq!(parse("2147483648").unwrap(), top(AST::BigInteger(String::from("2147483648")).into_boxed()));
+        assert_eq!(parse("- x").unwrap(), top(AST::CallMethod{object: var("x"), name: String::from("-"), arguments: Vec::new()}.into_boxed()));
+        assert_eq!(parse("x <- y <- 1.5e1").unwrap(), top(AST::AssignVariable{name: String::from("x"),
+            value: AST::AssignVariable{name: String::from("y"), value: AST::Float(15.0).into_boxed()}.into_boxed()}.into_boxed()));
+    }
+
+    #[test]
+    fn annotations() {
+        let ast = parse("let x: int = 1; function f(a, b: array): null -> null; function g() -> 1").unwrap();
+        let AST::Top(stmts) = &ast else { unreachable!() };
+        assert_eq!(*stmts[0], AST::Variable{name: String::from("x"), annotation: Some(Type::Int), value: AST::Integer(1).into_boxed()});
+        assert!(matches!(&*stmts[1], AST::Function{signature, ..}
+            if *signature == Signature{parameters: [None, Some(Type::Array)].to_vec(), returns: Some(Type::Null)}));
+        assert!(matches!(&*stmts[2], AST::Function{signature, ..} if signature.is_empty()));
+
+        // Annotations survive the JSON, unannotated nodes look as before.
+        let json = serde_json::to_string(&ast).unwrap();
+        assert_eq!(serde_json::from_str::<AST>(&json).unwrap(), ast);
+        assert!(json.contains(r#"{"Function":{"name":"g","parameters":[],"body":{"Integer":1}}}"#), "{}", json);
+        assert_eq!(parse("let x: string = 1"), Err(String::from("Line 1: Expected a type, found 'string'.")));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse("let x = ;"), Err(String::from("Line 1: Expected an expression, found ';'.")));
+        assert_eq!(parse("begin\n  1;\n  2\n  3\nend"), Err(String::from("Line 4: Expected 'end', found '3'.")));
+        assert_eq!(parse("1 + 2 <- 3"), Err(String::from("Line 1: Only variables, fields and array elements can be assigned.")));
+        assert_eq!(parse("\"abc"), Err(String::from("Line 1: Unterminated string.")));
+        assert_eq!(parse("let if = 1"), Err(String::from("Line 1: Expected a name, found 'if'.")));
+    }
+}
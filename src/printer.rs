@@ -0,0 +1,453 @@
+use crate::ast::{AST, Type};
+use crate::parser::{BINARY_LEVELS, UNARY_OPERATORS};
+
+/// Expressions that extend as far to the right as possible, like 'let' or 'if'.
+const OPEN: u8 = 0;
+const UNARY: u8 = BINARY_LEVELS.len() as u8 + 1;
+const POSTFIX: u8 = UNARY + 1;
+
+const INDENT: &str = "    ";
+
+/// Returns how tightly the expression binds, operands binding less
+/// tightly than their position requires are put in parentheses.
+fn precedence(ast: &AST) -> u8 {
+    match ast {
+        AST::CallMethod{object:_, name, arguments} => match (arguments.len(), binary_level(name)) {
+            (1, Some(level)) => level as u8 + 1,
+            (0, _) if UNARY_OPERATORS.contains(&name.as_str()) => UNARY,
+            _ => POSTFIX,
+        },
+        AST::Integer(val) if *val < 0 => UNARY,
+        AST::BigInteger(digits) if digits.starts_with('-') => UNARY,
+        AST::Float(val) if val.is_sign_negative() => UNARY,
+        AST::Variable{..} | AST::Function{..} | AST::Conditional{..} | AST::Loop{..} | AST::For{..} | AST::Try{..}
+        | AST::Throw{..} | AST::Return{..} | AST::Import{..} | AST::AssignVariable{..} | AST::AssignField{..}
+        | AST::AssignArray{..} | AST::Top(_) => OPEN,
+        _ => POSTFIX,
+    }
+}
+
+fn binary_level(name: &str) -> Option<usize> {
+    BINARY_LEVELS.iter().position(|level| level.contains(&name))
+}
+
+fn annotated(annotation: Option<Type>) -> String {
+    match annotation {
+        Some(annotation) => format!(": {}", annotation),
+        None => String::new(),
+    }
+}
+
+/// Returns true if the expression ends with 'if' without 'else' or 'try'
+/// without 'finally', which would take an 'else' or 'finally' following it.
+fn dangling(ast: &AST) -> bool {
+    match ast {
+        AST::Conditional{condition:_, consequent:_, alternative} => **alternative == AST::Null || dangling(alternative),
+        AST::Try{body:_, name:_, handler, finally} => **finally == AST::Null || dangling(handler),
+        AST::Loop{condition:_, body} | AST::For{name:_, iterable:_, body} | AST::Function{name:_, parameters:_, signature:_, body} => dangling(body),
+        AST::Variable{name:_, annotation:_, value} | AST::AssignVariable{name:_, value} | AST::AssignField{object:_, field:_, value}
+        | AST::AssignArray{array:_, index:_, value} | AST::Throw{value} | AST::Return{value} => dangling(value),
+        _ => false,
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut res = String::new();
+    for c in text.chars() {
+        match c {
+            '\\' => res += "\\\\",
+            '"' => res += "\\\"",
+            '\n' => res += "\\n",
+            '\t' => res += "\\t",
+            '\r' => res += "\\r",
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+struct Printer {
+    out: String,
+    depth: usize,
+    /// With a limit only the first line is printed, up to about 'limit' bytes.
+    /// The rest of the tree is skipped and 'cut' is set.
+    limit: Option<usize>,
+    cut: bool,
+}
+
+impl Printer {
+    fn newline(&mut self) {
+        if self.cut {
+            return;
+        }
+        self.out.push('\n');
+        if self.limit.is_some() {
+            self.cut = true;
+            return;
+        }
+        for _ in 0..self.depth {
+            self.out += INDENT;
+        }
+    }
+
+    /// Prints the expression, in parentheses if it binds less tightly than 'min'.
+    fn expr(&mut self, ast: &AST, min: u8) {
+        if precedence(ast) < min {
+            self.out.push('(');
+            self.print(ast);
+            self.out.push(')');
+        } else {
+            self.print(ast);
+        }
+    }
+
+    /// Prints the expression after a keyword, blocks start on a new line.
+    fn body(&mut self, keyword: &str, ast: &AST) {
+        self.out += keyword;
+        if let AST::Block(_) = ast {
+            self.newline();
+        } else {
+            self.out.push(' ');
+        }
+        self.expr(ast, OPEN);
+    }
+
+    fn sequence(&mut self, exprs: &[Box<AST>]) {
+        self.depth += 1;
+        for (i, expr) in exprs.iter().enumerate() {
+            if self.cut {
+                break;
+            }
+            self.newline();
+            self.expr(expr, OPEN);
+            if i + 1 != exprs.len() {
+                self.out.push(';');
+            }
+        }
+        self.depth -= 1;
+        self.newline();
+    }
+
+    fn list(&mut self, exprs: &[Box<AST>]) {
+        for (i, expr) in exprs.iter().enumerate() {
+            if self.cut {
+                break;
+            }
+            if i != 0 {
+                self.out += ", ";
+            }
+            self.expr(expr, OPEN);
+        }
+    }
+
+    fn print(&mut self, ast: &AST) {
+        if self.limit.is_some_and(|limit| self.out.len() > limit) {
+            self.cut = true;
+        }
+        if self.cut {
+            return;
+        }
+        match ast {
+            AST::Integer(val) => self.out += &val.to_string(),
+            AST::BigInteger(digits) => self.out += digits,
+            AST::Float(val) => self.out += &format!("{:?}", val),
+            AST::String(val) => self.out += &format!("\"{}\"", escape(val)),
+            AST::Boolean(val) => self.out += &val.to_string(),
+            AST::Null => self.out += "null",
+
+            AST::Variable{name, annotation, value} => {
+                self.out += &format!("let {}{} =", name, annotated(*annotation));
+                self.body("", value);
+            }
+            AST::Array{size, value} => {
+                self.out += "array(";
+                self.expr(size, OPEN);
+                self.out += ", ";
+                self.expr(value, OPEN);
+                self.out.push(')');
+            }
+            AST::Object{extends, members} => {
+                self.out += "object ";
+                if **extends != AST::Null {
+                    self.out += "extends ";
+                    self.expr(extends, 1);
+                    self.out.push(' ');
+                }
+                self.out += "begin";
+                self.sequence(members);
+                self.out += "end";
+            }
+
+            AST::AccessVariable{name} => self.out += name,
+            AST::AccessField{object, field} => {
+                self.expr(object, POSTFIX);
+                self.out += &format!(".{}", field);
+            }
+            AST::AccessArray{array, index} => {
+                self.expr(array, POSTFIX);
+                self.out.push('[');
+                self.expr(index, OPEN);
+                self.out.push(']');
+            }
+
+            AST::AssignVariable{name, value} => {
+                self.out += name;
+                self.body(" <-", value);
+            }
+            AST::AssignField{object, field, value} => {
+                self.expr(object, POSTFIX);
+                self.out += &format!(".{}", field);
+                self.body(" <-", value);
+            }
+            AST::AssignArray{array, index, value} => {
+                self.expr(array, POSTFIX);
+                self.out.push('[');
+                self.expr(index, OPEN);
+                self.out.push(']');
+                self.body(" <-", value);
+            }
+
+            AST::Function{name, parameters, signature, body} => {
+                let parameters: Vec<String> = parameters.iter().enumerate()
+                    .map(|(index, parameter)| format!("{}{}", parameter, annotated(signature.parameter(index))))
+                    .collect();
+                self.out += &format!("function {}({}){} ->", name, parameters.join(", "), annotated(signature.returns));
+                self.body("", body);
+            }
+            AST::CallFunction{name, arguments} => {
+                self.out += name;
+                self.out.push('(');
+                self.list(arguments);
+                self.out.push(')');
+            }
+            AST::CallMethod{object, name, arguments} => match precedence(ast) {
+                UNARY => {
+                    self.out += name;
+                    let start = self.out.len();
+                    self.expr(object, UNARY);
+                    // '-1' would be read as a negative literal.
+                    if name == "-" && self.out[start..].starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+                        self.out.insert(start, '(');
+                        self.out.push(')');
+                    }
+                }
+                POSTFIX => {
+                    self.expr(object, POSTFIX);
+                    self.out += &format!(".{}(", name);
+                    self.list(arguments);
+                    self.out.push(')');
+                }
+                level => {
+                    self.expr(object, level);
+                    self.out += &format!(" {} ", name);
+                    self.expr(&arguments[0], level + 1);
+                }
+            },
+
+            AST::Top(exprs) => {
+                for (i, expr) in exprs.iter().enumerate() {
+                    if self.cut {
+                        break;
+                    }
+                    self.expr(expr, OPEN);
+                    self.out += if i + 1 != exprs.len() { ";\n" } else { "\n" };
+                }
+            }
+            AST::Block(exprs) => {
+                self.out += "begin";
+                self.sequence(exprs);
+                self.out += "end";
+            }
+            AST::Loop{condition, body} => {
+                self.out += "while ";
+                self.expr(condition, OPEN);
+                self.body(" do", body);
+            }
+            AST::For{name, iterable, body} => {
+                self.out += &format!("for {} in ", name);
+                self.expr(iterable, OPEN);
+                self.body(" do", body);
+            }
+            AST::Conditional{condition, consequent, alternative} => {
+                self.out += "if ";
+                self.expr(condition, OPEN);
+                let has_else = **alternative != AST::Null;
+                if has_else && dangling(consequent) {
+                    self.out += " then (";
+                    self.print(consequent);
+                    self.out.push(')');
+                } else {
+                    self.body(" then", consequent);
+                }
+                if has_else {
+                    self.newline();
+                    self.body("else", alternative);
+                }
+            }
+            AST::Print{format, arguments} => {
+                // Escapes of the format are kept as written in the source.
+                self.out += &format!("print(\"{}\"", format);
+                for argument in arguments {
+                    if self.cut {
+                        break;
+                    }
+                    self.out += ", ";
+                    self.expr(argument, OPEN);
+                }
+                self.out.push(')');
+            }
+            AST::Throw{value} => self.body("throw", value),
+            AST::Try{body, name, handler, finally} => {
+                self.body("try", body);
+                self.newline();
+                let has_finally = **finally != AST::Null;
+                if has_finally && dangling(handler) {
+                    self.out += &format!("catch {} -> (", name);
+                    self.print(handler);
+                    self.out.push(')');
+                } else {
+                    self.body(&format!("catch {} ->", name), handler);
+                }
+                if has_finally {
+                    self.newline();
+                    self.body("finally", finally);
+                }
+            }
+            AST::Return{value} => self.body("return", value),
+            AST::Break => self.out += "break",
+            AST::Continue => self.out += "continue",
+            AST::Import{path, name} => {
+                self.out += &format!("import \"{}\"", escape(path));
+                if let Some(name) = name {
+                    self.out += &format!(" as {}", name);
+                }
+            }
+        }
+    }
+}
+
+/// Renders the tree as canonical FML source.
+pub fn format(ast: &AST) -> String {
+    let mut printer = Printer{out: String::new(), depth: 0, limit: None, cut: false};
+    printer.print(ast);
+    printer.out
+}
+
+/// Renders the first line of the tree, longer sources are cut after
+/// 'width' characters. Only as much of the tree is printed as is shown.
+pub fn summary(ast: &AST, width: usize) -> String {
+    // Characters take at most 4 bytes.
+    let mut printer = Printer{out: String::new(), depth: 0, limit: Some(width * 4), cut: false};
+    printer.print(ast);
+    let mut lines = printer.out.lines();
+    let line = lines.next().unwrap_or_default();
+    let shortened: String = line.chars().take(width).collect();
+    if printer.cut || shortened.len() < line.len() || lines.next().is_some() {
+        format!("{}...", shortened)
+    } else {
+        shortened
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::Signature;
+    use crate::parser::parse;
+
+    fn assert_round_trip(ast: &AST) {
+        let source = format(ast);
+        let parsed = parse(&source).unwrap_or_else(|err| panic!("{}\n{}", err, source));
+        assert_eq!(&parsed, ast, "{}", source);
+        assert_eq!(format(&parsed), source);
+    }
+
+    #[test]
+    fn summaries() {
+        let summary = |source: &str, width: usize| super::summary(&parse(source).unwrap(), width);
+        assert_eq!(summary("f(1, 2)", 10), "f(1, 2)");
+        assert_eq!(summary("f(1, 2)", 3), "f(1...");
+        let call = AST::CallFunction{name: String::from("f"), arguments: [AST::String(String::from("\u{e9}t\u{e9}")).into_boxed()].to_vec()};
+        assert_eq!(super::summary(&call, 8), "f(\"\u{e9}t\u{e9}\")");
+        assert_eq!(super::summary(&call, 7), "f(\"\u{e9}t\u{e9}\"...");
+        assert_eq!(super::summary(&AST::Block([AST::Integer(1).into_boxed()].to_vec()), 10), "begin...");
+        // Only the shown part of a long tree is printed.
+        let long = AST::Top((0..100000).map(|i| AST::Integer(i).into_boxed()).collect());
+        assert_eq!(super::summary(&long, 5), "0;...");
+        let mut printer = Printer{out: String::new(), depth: 0, limit: Some(20), cut: false};
+        printer.print(&AST::CallFunction{name: String::from("f"), arguments: (0..100000).map(|i| AST::Integer(i).into_boxed()).collect()});
+        assert!(printer.cut && printer.out.len() < 100, "{}", printer.out);
+    }
+
+    #[test]
+    fn examples_round_trip() {
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "fml") {
+                let source = std::fs::read_to_string(&path).unwrap();
+                assert_round_trip(&parse(&source).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn all_nodes_round_trip() {
+        let var = |name: &str| AST::AccessVariable{name: String::from(name)}.into_boxed();
+        let int = |val: i32| AST::Integer(val).into_boxed();
+        let op = |left: Box<AST>, name: &str, right: Box<AST>| AST::CallMethod{object: left, name: String::from(name), arguments: [right].to_vec()}.into_boxed();
+        let unary = |name: &str, object: Box<AST>| AST::CallMethod{object, name: String::from(name), arguments: Vec::new()}.into_boxed();
+        let open_if = || AST::Conditional{condition: var("a"), consequent: int(1), alternative: AST::Null.into_boxed()}.into_boxed();
+        let ast = AST::Top([
+            AST::Variable{name: String::from("x"), annotation: Some(Type::Int), value: op(op(int(1), "+", int(2)), "*", op(int(3), "-", op(int(4), "-", int(5))))}.into_boxed(),
+            AST::Variable{name: String::from("y"), annotation: None, value: AST::Block([
+                AST::Variable{name: String::from("z"), annotation: None, value: AST::Float(-0.25).into_boxed()}.into_boxed(),
+                op(AST::BigInteger(String::from("-99999999999")).into_boxed(), ">>>", AST::Float(1e100).into_boxed()),
+            ].to_vec()).into_boxed()}.into_boxed(),
+            unary("-", int(5)),
+            unary("-", int(-5)),
+            unary("-", AST::CallMethod{object: int(1), name: String::from("m"), arguments: Vec::new()}.into_boxed()),
+            unary("!", unary("~", op(var("a"), "|", var("b")))),
+            op(int(-1), "-", int(-1)),
+            AST::CallMethod{object: op(var("a"), "+", var("b")), name: String::from("+"), arguments: [int(1), int(2)].to_vec()}.into_boxed(),
+            AST::Function{name: String::from("=="), parameters: [String::from("other"), String::from("rest")].to_vec(),
+                signature: Signature{parameters: [Some(Type::Object), None].to_vec(), returns: Some(Type::Bool)}, body: AST::Return{value: AST::Boolean(false).into_boxed()}.into_boxed()}.into_boxed(),
+            AST::Function{name: String::from("f"), parameters: Vec::new(), signature: Signature::default(), body: AST::Block(Vec::new()).into_boxed()}.into_boxed(),
+            AST::Object{extends: op(var("p"), "+", int(1)), members: [
+                AST::Variable{name: String::from("v"), annotation: Some(Type::Any), value: AST::Null.into_boxed()}.into_boxed(),
+            ].to_vec().into()}.into_boxed(),
+            AST::Object{extends: AST::Null.into_boxed(), members: Vec::new().into()}.into_boxed(),
+            AST::AssignField{object: AST::CallFunction{name: String::from("g"), arguments: [var("x"), AST::String(String::from("a\"b\\c\n")).into_boxed()].to_vec()}.into_boxed(),
+                field: String::from("f"), value: AST::AssignArray{array: AST::AccessField{object: var("o"), field: String::from("arr")}.into_boxed(),
+                index: AST::AccessArray{array: var("i"), index: int(0)}.into_boxed(), value: AST::AssignVariable{name: String::from("q"), value: int(1)}.into_boxed()}.into_boxed()}.into_boxed(),
+            op(var("a"), "+", AST::Conditional{condition: var("b"), consequent: int(1), alternative: int(2)}.into_boxed()),
+            AST::Conditional{condition: AST::Conditional{condition: var("a"), consequent: int(1), alternative: int(2)}.into_boxed(),
+                consequent: AST::Loop{condition: var("c"), body: open_if()}.into_boxed(), alternative: open_if()}.into_boxed(),
+            AST::Array{size: int(3), value: AST::Object{extends: AST::Null.into_boxed(), members: Vec::new().into()}.into_boxed()}.into_boxed(),
+            AST::For{name: String::from("i"), iterable: AST::CallFunction{name: String::from("range"), arguments: [int(0), int(3)].to_vec()}.into_boxed(),
+                body: AST::Block([AST::Break.into_boxed(), AST::Continue.into_boxed()].to_vec()).into_boxed()}.into_boxed(),
+            AST::Try{body: AST::Throw{value: int(1)}.into_boxed(), name: String::from("e"),
+                handler: AST::Try{body: int(2), name: String::from("f"), handler: int(3), finally: AST::Null.into_boxed()}.into_boxed(),
+                finally: AST::Print{format: String::from("done ~\\n"), arguments: [var("e")].to_vec()}.into_boxed()}.into_boxed(),
+            AST::Import{path: String::from("lib/stack.fml"), name: Some(String::from("stack"))}.into_boxed(),
+            AST::Import{path: String::from("other.fml"), name: None}.into_boxed(),
+        ].to_vec());
+        assert_round_trip(&ast);
+    }
+
+    #[test]
+    fn layout() {
+        let source = "function f(x) -> begin if x then print(\"yes\\n\") else begin let y = x; y end end; f(1)";
+        assert_eq!(format(&parse(source).unwrap()), "\
+function f(x) ->
+begin
+    if x then print(\"yes\\n\")
+    else
+    begin
+        let y = x;
+        y
+    end
+end;
+f(1)
+");
+    }
+}
@@ -0,0 +1,321 @@
+use crate::ast::AST;
+use crate::heap::{Allocated, Allocation};
+use crate::printer;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// Statistics of one function or method.
+#[derive(Clone, Default, Debug)]
+pub struct Stats {
+    pub calls: u64,
+    /// Time spent in the calls including their callees. Recursive calls are
+    /// counted only by the outermost one.
+    pub total: Duration,
+    /// Time spent in the bodies alone.
+    pub exclusive: Duration,
+    /// Allocations made by the bodies alone.
+    pub allocated: Allocated,
+}
+
+/// Call being executed.
+struct Frame {
+    label: usize,
+    start: Instant,
+    allocated: Allocated,
+    /// Time and allocations of the calls made by this one so far.
+    children: Duration,
+    children_allocated: Allocated,
+}
+
+/// Collects statistics of the calls of functions and methods, the runtime
+/// reports every call by `enter` and `exit`.
+#[derive(Default)]
+pub struct Profiler {
+    labels: HashMap<String, usize>,
+    stats: Vec<(String, Stats)>,
+    stack: Vec<Frame>,
+    /// Exclusive time per stack of labels, for flame graphs.
+    folded: HashMap<Vec<usize>, Duration>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    /// Starts call of function 'label', 'allocated' are the current totals of the heap.
+    pub fn enter(&mut self, label: &str, allocated: Allocated) {
+        let label = match self.labels.get(label) {
+            Some(label) => *label,
+            None => {
+                self.stats.push((label.to_string(), Stats::default()));
+                self.labels.insert(label.to_string(), self.stats.len() - 1);
+                self.stats.len() - 1
+            }
+        };
+        self.stack.push(Frame{label, start: Instant::now(), allocated, children: Duration::ZERO, children_allocated: Allocated::default()});
+    }
+
+    /// Ends the call started by the last `enter`.
+    pub fn exit(&mut self, allocated: Allocated) {
+        let elapsed_allocated = allocated - self.stack.last().expect("Exit without a call.").allocated;
+        let labels: Vec<usize> = self.stack.iter().map(|frame| frame.label).collect();
+        let frame = self.stack.pop().expect("Exit without a call.");
+        let elapsed = frame.start.elapsed();
+        let exclusive = elapsed.saturating_sub(frame.children);
+        let recursive = self.stack.iter().any(|caller| caller.label == frame.label);
+        let stats = &mut self.stats[frame.label].1;
+        stats.calls += 1;
+        if !recursive {
+            stats.total += elapsed;
+        }
+        stats.exclusive += exclusive;
+        stats.allocated.count += elapsed_allocated.count - frame.children_allocated.count;
+        stats.allocated.bytes += elapsed_allocated.bytes - frame.children_allocated.bytes;
+        *self.folded.entry(labels).or_default() += exclusive;
+        if let Some(caller) = self.stack.last_mut() {
+            caller.children += elapsed;
+            caller.children_allocated.count += elapsed_allocated.count;
+            caller.children_allocated.bytes += elapsed_allocated.bytes;
+        }
+    }
+
+    /// Returns statistics of all called functions, the most expensive first.
+    pub fn stats(&self) -> Vec<&(String, Stats)> {
+        let mut stats: Vec<&(String, Stats)> = self.stats.iter().collect();
+        stats.sort_by(|(left_label, left), (right_label, right)| {
+            right.exclusive.cmp(&left.exclusive).then_with(|| left_label.cmp(right_label))
+        });
+        stats
+    }
+
+    /// Writes table of the statistics sorted by exclusive time.
+    pub fn report(&self, out: &mut dyn Write) -> io::Result<()> {
+        let stats = self.stats();
+        let width = stats.iter().map(|(label, _)| label.len()).max().unwrap_or(0).max("function".len());
+        writeln!(out, "{:<width$} {:>10} {:>12} {:>12} {:>10} {:>12}", "function", "calls", "total ms", "self ms", "allocs", "alloc bytes")?;
+        for (label, stats) in stats {
+            writeln!(out, "{:<width$} {:>10} {:>12.3} {:>12.3} {:>10} {:>12}", label, stats.calls,
+                stats.total.as_secs_f64() * 1000.0, stats.exclusive.as_secs_f64() * 1000.0, stats.allocated.count, stats.allocated.bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Writes exclusive time in microseconds per call stack in the folded
+    /// format read by flame graph tools, one 'caller;callee time' per line.
+    pub fn write_folded(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut lines: Vec<(String, u128)> = self.folded.iter()
+            .map(|(labels, time)| {
+                let stack: Vec<&str> = labels.iter().map(|label| self.stats[*label].0.as_str()).collect();
+                (stack.join(";"), time.as_micros())
+            })
+            .collect();
+        lines.sort();
+        for (stack, time) in lines {
+            writeln!(out, "{} {}", stack, time)?;
+        }
+        Ok(())
+    }
+}
+
+/// Place in the program that allocates.
+struct Site {
+    function: String,
+    /// Source of the node, shortened to its first line.
+    node: String,
+    allocated: Allocated,
+}
+
+/// Longest source of a node shown in the report.
+const NODE_WIDTH: usize = 40;
+
+/// Attributes heap allocations to the nodes that made them and to the
+/// functions enclosing those nodes, the runtime reports them by `record`.
+#[derive(Default)]
+pub struct AllocationProfiler {
+    /// Sites keyed by the address of the node, nodes of the runtime itself
+    /// are under the null address.
+    sites: HashMap<*const AST, Site>,
+    kinds: HashMap<&'static str, Allocated>,
+}
+
+impl AllocationProfiler {
+    pub fn new() -> Self {
+        AllocationProfiler::default()
+    }
+
+    /// Attributes 'allocations' to 'node' of 'function', without the node
+    /// they were made by the runtime outside of any evaluation.
+    pub fn record(&mut self, function: &str, node: Option<&AST>, allocations: &[Allocation]) {
+        let address = node.map_or(std::ptr::null(), |node| node as *const AST);
+        let site = self.sites.entry(address).or_insert_with(|| {
+            let node = node.map_or_else(|| String::from("<runtime>"), |node| printer::summary(node, NODE_WIDTH));
+            Site{function: function.to_string(), node, allocated: Allocated::default()}
+        });
+        for allocation in allocations {
+            site.allocated.count += 1;
+            site.allocated.bytes += allocation.bytes;
+            let kind = self.kinds.entry(allocation.kind).or_default();
+            kind.count += 1;
+            kind.bytes += allocation.bytes;
+        }
+    }
+
+    /// Returns function, node and allocations of every site, the most bytes first.
+    pub fn sites(&self) -> Vec<(&str, &str, Allocated)> {
+        let mut sites: Vec<(&str, &str, Allocated)> = self.sites.values()
+            .map(|site| (site.function.as_str(), site.node.as_str(), site.allocated))
+            .collect();
+        sites.sort_by(|left, right| right.2.bytes.cmp(&left.2.bytes).then_with(|| (left.0, left.1).cmp(&(right.0, right.1))));
+        sites
+    }
+
+    /// Returns allocations per type of the allocated values, the most bytes first.
+    pub fn kinds(&self) -> Vec<(&'static str, Allocated)> {
+        let mut kinds: Vec<(&'static str, Allocated)> = self.kinds.iter().map(|(kind, allocated)| (*kind, *allocated)).collect();
+        kinds.sort_by(|left, right| right.1.bytes.cmp(&left.1.bytes).then_with(|| left.0.cmp(right.0)));
+        kinds
+    }
+
+    /// Writes tables of allocations per site and per type.
+    pub fn report(&self, out: &mut dyn Write) -> io::Result<()> {
+        let sites = self.sites();
+        let width = sites.iter().map(|(function, _, _)| function.len()).max().unwrap_or(0).max("function".len());
+        writeln!(out, "{:<width$} {:<node$} {:>10} {:>12}", "function", "site", "allocs", "bytes", node = NODE_WIDTH + 3)?;
+        for (function, node, allocated) in sites {
+            writeln!(out, "{:<width$} {:<node$} {:>10} {:>12}", function, node, allocated.count, allocated.bytes, node = NODE_WIDTH + 3)?;
+        }
+        writeln!(out)?;
+        writeln!(out, "{:<8} {:>10} {:>12}", "kind", "allocs", "bytes")?;
+        for (kind, allocated) in self.kinds() {
+            writeln!(out, "{:<8} {:>10} {:>12}", kind, allocated.count, allocated.bytes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interpreter::{interpret_instrumented, Instrumentation};
+    use crate::parser::parse;
+    use serial_test::serial;
+
+    #[test]
+    fn nested_calls() {
+        let allocated = |count: u64| Allocated{count, bytes: count * 8};
+        let mut profiler = Profiler::new();
+        profiler.enter("main", allocated(0));
+        profiler.enter("fib", allocated(1));
+        profiler.enter("fib", allocated(1));
+        profiler.exit(allocated(3));
+        profiler.exit(allocated(4));
+        profiler.enter("print", allocated(4));
+        profiler.exit(allocated(4));
+        profiler.exit(allocated(6));
+
+        let stats: HashMap<&str, &Stats> = profiler.stats().into_iter().map(|(label, stats)| (label.as_str(), stats)).collect();
+        assert_eq!(stats["fib"].calls, 2);
+        assert_eq!(stats["fib"].allocated, allocated(3));
+        assert_eq!(stats["main"].allocated, allocated(3));
+        assert_eq!(stats["print"].allocated, allocated(0));
+        assert!(stats["main"].total >= stats["fib"].total + stats["print"].total);
+        assert!(stats["fib"].total >= stats["fib"].exclusive);
+
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded).unwrap();
+        let stacks: Vec<&str> = std::str::from_utf8(&folded).unwrap().lines().map(|line| line.rsplit_once(' ').unwrap().0).collect();
+        assert_eq!(stacks, ["main", "main;fib", "main;fib;fib", "main;print"]);
+    }
+
+    #[test]
+    #[serial]
+    fn program() {
+        let ast = parse("\
+function fib(n) -> if n < 2 then n else fib(n - 1) + fib(n - 2);
+function loop(n) -> if n > 0 then loop(n - 1) else array(n, 0);
+let o = object begin function m() -> 1 + fib(3) end;
+fib(5); loop(3); o.m()").unwrap();
+        let mut instrumentation = Instrumentation{profiler: Some(Profiler::new()), ..Default::default()};
+        interpret_instrumented(&ast, None, &[], Box::new(io::empty()), &mut io::sink(), &mut instrumentation).unwrap();
+        let profiler = instrumentation.profiler.unwrap();
+
+        let stats: HashMap<&str, &Stats> = profiler.stats().into_iter().map(|(label, stats)| (label.as_str(), stats)).collect();
+        assert_eq!(stats["fib"].calls, 15 + 5);
+        assert_eq!(stats["loop"].calls, 4);
+        assert_eq!(stats["loop"].allocated.count, 2);
+        assert_eq!(stats["object#0.m"].calls, 1);
+        let mut report = Vec::new();
+        profiler.report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("function "), "{}", report);
+        assert_eq!(report.lines().count(), 4);
+
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        // Tail calls replace the caller.
+        assert!(folded.lines().any(|line| line.starts_with("loop ")), "{}", folded);
+        assert!(!folded.contains("loop;loop"), "{}", folded);
+        assert!(folded.lines().any(|line| line.starts_with("object#0.m;fib;fib ")), "{}", folded);
+    }
+
+    #[test]
+    #[serial]
+    fn classes() {
+        let ast = parse("\
+function point(x) -> object begin let x = x; function m() -> this.x end;
+let a = object begin function m() -> 0 end;
+point(1).m(); point(2).m(); a.m()").unwrap();
+        let mut instrumentation = Instrumentation{profiler: Some(Profiler::new()), ..Default::default()};
+        interpret_instrumented(&ast, None, &[], Box::new(io::empty()), &mut io::sink(), &mut instrumentation).unwrap();
+        let profiler = instrumentation.profiler.unwrap();
+
+        // Objects of the same literal share the class, other literals have their own.
+        // The literal in 'point' is numbered second, it's evaluated only by the call.
+        let stats: HashMap<&str, &Stats> = profiler.stats().into_iter().map(|(label, stats)| (label.as_str(), stats)).collect();
+        assert_eq!(stats["object#0.m"].calls, 1);
+        assert_eq!(stats["object#1.m"].calls, 2);
+    }
+
+    #[test]
+    #[serial]
+    fn allocations() {
+        let ast = parse("\
+function make(n) -> array(n, 0);
+let a = make(2); make(3);
+let o = object begin let x = 1.5 end;
+let big = 2147483647 + 1;
+let s = \"text\"").unwrap();
+        let mut instrumentation = Instrumentation{allocations: Some(AllocationProfiler::new()), ..Default::default()};
+        interpret_instrumented(&ast, None, &["arg".to_string()], Box::new(io::empty()), &mut io::sink(), &mut instrumentation).unwrap();
+        let allocations = instrumentation.allocations.unwrap();
+
+        let sites: HashMap<(&str, &str), Allocated> = allocations.sites().into_iter().map(|(function, node, allocated)| ((function, node), allocated)).collect();
+        // Arrays allocate their elements separately.
+        assert_eq!(sites[&("make", "array(n, 0)")].count, 4);
+        assert_eq!(sites[&("<top level>", "object begin...")].count, 1);
+        assert_eq!(sites[&("<top level>", "1.5")].count, 1);
+        assert_eq!(sites[&("<top level>", "2147483647 + 1")].count, 1);
+        assert_eq!(sites[&("<top level>", "\"text\"")].count, 1);
+        // The array of command line arguments and its string.
+        assert_eq!(sites[&("<top level>", "<runtime>")].count, 3);
+        assert_eq!(sites.len(), 6);
+
+        let kinds: HashMap<&str, Allocated> = allocations.kinds().into_iter().collect();
+        assert_eq!(kinds["array"].count, 6);
+        assert_eq!(kinds["object"].count, 1);
+        assert_eq!(kinds["float"].count, 1);
+        assert_eq!(kinds["int"].count, 1);
+        assert_eq!(kinds["string"].count, 2);
+        let total: u64 = kinds.values().map(|allocated| allocated.bytes).sum();
+        assert_eq!(total, sites.values().map(|allocated| allocated.bytes).sum::<u64>());
+
+        let mut report = Vec::new();
+        allocations.report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("function "), "{}", report);
+        assert!(report.contains("\nkind "), "{}", report);
+    }
+}
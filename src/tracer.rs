@@ -0,0 +1,199 @@
+use std::io::Write;
+
+/// Kinds of the traced events, used by the filters.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// Evaluation of any node that isn't an assignment.
+    Node,
+    /// Evaluation of 'let' or of an assignment.
+    Assignment,
+    /// Call of a function or a method defined by the program.
+    Call,
+    /// Push, pop, save or restore of the environment.
+    Environment,
+    /// Variable bound to a value.
+    Binding,
+}
+
+/// Restricts the trace to some kinds of events.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Only {
+    Calls,
+    Assignments,
+}
+
+/// Logs evaluation step by step, indented by nesting of the shown nodes
+/// and calls. The runtime opens a scope by `enter` for every node and call
+/// and closes it by `exit` with its result, other events are logged by
+/// `event`. Texts are rendered by the runtime only if `shows` allows it.
+/// Write errors are ignored, the trace mustn't change the run.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    pub only: Option<Only>,
+    /// Events nested deeper are left out.
+    pub max_depth: Option<usize>,
+    /// Whether the open scopes are shown, the innermost last.
+    open: Vec<bool>,
+    /// Number of the shown open scopes.
+    depth: usize,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Tracer{out, only: None, max_depth: None, open: Vec::new(), depth: 0}
+    }
+
+    /// Returns true if event of 'kind' is shown at the current depth.
+    pub fn shows(&self, kind: Kind) -> bool {
+        let wanted = match self.only {
+            None => true,
+            Some(Only::Calls) => kind == Kind::Call,
+            Some(Only::Assignments) => kind == Kind::Assignment || kind == Kind::Binding,
+        };
+        wanted && self.max_depth.is_none_or(|max_depth| self.depth < max_depth)
+    }
+
+    /// Opens scope shown as 'text', without it the scope is hidden.
+    pub fn enter(&mut self, text: Option<&str>) {
+        if let Some(text) = text {
+            self.line(text);
+            self.depth += 1;
+        }
+        self.open.push(text.is_some());
+    }
+
+    /// Returns true if the innermost scope is shown, only then its result is.
+    pub fn exits_shown(&self) -> bool {
+        self.open.last() == Some(&true)
+    }
+
+    /// Closes the innermost scope, 'result' is shown if the scope is.
+    pub fn exit(&mut self, result: Option<&str>) {
+        if self.open.pop().expect("Exit without a scope.") {
+            self.depth -= 1;
+            if let Some(result) = result {
+                self.line(&format!("=> {}", result));
+            }
+        }
+    }
+
+    /// Logs event that doesn't open a scope.
+    pub fn event(&mut self, text: &str) {
+        self.line(text);
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+
+    fn line(&mut self, text: &str) {
+        let _ = writeln!(self.out, "{}{}", "  ".repeat(self.depth), text);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interpreter::{interpret_instrumented, Instrumentation};
+    use crate::parser::parse;
+    use serial_test::serial;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    /// Writer whose contents can be read while the tracer owns it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    fn trace(source: &str, only: Option<Only>, max_depth: Option<usize>) -> String {
+        let ast = parse(source).unwrap();
+        let out = Shared::default();
+        let mut tracer = Tracer::new(Box::new(out.clone()));
+        tracer.only = only;
+        tracer.max_depth = max_depth;
+        let mut instrumentation = Instrumentation{tracer: Some(tracer), ..Default::default()};
+        interpret_instrumented(&ast, None, &[], Box::new(io::empty()), &mut io::sink(), &mut instrumentation).unwrap();
+        out.text()
+    }
+
+    #[test]
+    fn scopes() {
+        let out = Shared::default();
+        let mut tracer = Tracer::new(Box::new(out.clone()));
+        tracer.max_depth = Some(2);
+        tracer.enter(Some("a"));
+        assert!(tracer.shows(Kind::Node));
+        tracer.enter(None);
+        tracer.enter(Some("b"));
+        assert!(!tracer.shows(Kind::Node));
+        tracer.exit(Some("1"));
+        tracer.event("c");
+        tracer.exit(Some("hidden"));
+        assert!(tracer.exits_shown());
+        tracer.exit(Some("2"));
+        assert_eq!(out.text(), "a\n  b\n  => 1\n  c\n=> 2\n");
+
+        tracer.only = Some(Only::Calls);
+        assert!(tracer.shows(Kind::Call));
+        assert!(!tracer.shows(Kind::Binding));
+        tracer.only = Some(Only::Assignments);
+        assert!(tracer.shows(Kind::Binding));
+        assert!(!tracer.shows(Kind::Environment));
+    }
+
+    #[test]
+    #[serial]
+    fn program() {
+        let source = "function inc(n) -> n + 1; let x = inc(1); x <- x * 2";
+        let full = trace(source, None, None);
+        let lines: Vec<&str> = full.lines().collect();
+        assert_eq!(lines[0], "let x = inc(1)", "{}", full);
+        assert!(lines.contains(&"  inc(1)"), "{}", full);
+        assert!(lines.contains(&"    call inc(1)"), "{}", full);
+        assert!(lines.contains(&"      save env"), "{}", full);
+        assert!(lines.contains(&"      bind n = 1"), "{}", full);
+        assert!(lines.contains(&"      restore env"), "{}", full);
+        assert!(lines.contains(&"  bind x = 2"), "{}", full);
+        assert_eq!(lines[lines.len() - 1], "=> 4", "{}", full);
+
+        assert_eq!(trace(source, Some(Only::Calls), None), "call inc(1)\n=> 2\n");
+        assert_eq!(trace(source, Some(Only::Assignments), None), "\
+let x = inc(1)
+  bind n = 1
+  bind x = 2
+=> 2
+x <- x * 2
+=> 4
+");
+        assert_eq!(trace(source, None, Some(1)), "let x = inc(1)\n=> 2\nx <- x * 2\n=> 4\n");
+    }
+
+    #[test]
+    #[serial]
+    fn values() {
+        let source = "\
+function f(n) -> if n == 0 then \"done\" else f(n - 1);
+let o = object begin let a = array(2, 0) end; f(1)";
+        let full = trace(source, Some(Only::Calls), None);
+        // Tail calls replace the caller.
+        assert_eq!(full, "call f(1)\n=> tail call f\ncall f(0)\n=> \"done\"\n");
+        let full = trace(source, Some(Only::Assignments), None);
+        assert!(full.contains("=> object\n"), "{}", full);
+    }
+}